@@ -0,0 +1,473 @@
+/*
+    Binary format for compiled scripts, saved in .qkc files.
+
+    The file starts with the magic bytes, the format version and a checksum of everything after it.
+    Then come the names of the globals the script was compiled against, in slot order, and the
+    top level script function. Its constants hold the functions declared in it, written the same way.
+
+    All integers are little endian, counts and lengths are u32.
+
+    Global slots are only meaningful to the VM that handed them out, so loading resolves every
+    name in the loading VM and rewrites the global operands in the code to its slots. The VM
+    loading a file may hand out slots above 255, so global instructions are always written in
+    their long form, whatever form the compiler chose.
+*/
+use crate::chunk::{Chunk, OpCode};
+use crate::globals::GlobalNames;
+use crate::object::{Object, ObjFunction, Interner};
+use crate::span::Span;
+use crate::value::Value;
+use crate::verifier::MAX_NESTING;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+pub const MAGIC: &[u8; 4] = b"QKC\0";
+pub const VERSION: u16 = 2;
+
+// size of the magic, version and checksum in front of the payload
+const HEADER_LEN: usize = 10;
+
+// tags of the constant types
+const TAG_VOID: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+// whether the bytes start like a compiled script, rather than source
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// serialize the script function, along with the global names its code refers to
+pub fn write(function: &ObjFunction, globals: &GlobalNames) -> Result<Vec<u8>, String> {
+    let mut payload = Writer { bytes: Vec::new() };
+
+    payload.write_len(globals.len())?;
+    for slot in 0..globals.len() {
+        payload.write_str(globals.name(slot))?;
+    }
+    payload.write_function(function)?;
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.bytes.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&checksum(&payload.bytes).to_le_bytes());
+    bytes.extend_from_slice(&payload.bytes);
+    Ok(bytes)
+}
+
+/*
+    Load a script function written by write.
+    Strings are interned in the VM's table and globals are resolved to the VM's slots. Names the
+    VM does not know yet are not added to it, they are handed back in the order of the slots the
+    code was given for them after the VM's own. The caller adds them once the script is verified.
+*/
+pub fn read(bytes: &[u8], strings: &mut Interner, globals: &GlobalNames) -> Result<(ObjFunction, Vec<String>), String> {
+    if bytes.len() < HEADER_LEN || !is_bytecode(bytes) {
+        return Err(String::from("Not a compiled quark file."));
+    }
+
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != VERSION {
+        return Err(format!("Unsupported bytecode version {}, expected {}.", version, VERSION));
+    }
+
+    let expected = u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]);
+    let payload = &bytes[HEADER_LEN..];
+    if checksum(payload) != expected {
+        return Err(String::from("Bytecode checksum does not match, the file is corrupted."));
+    }
+
+    let mut reader = Reader { bytes: payload, position: 0, strings, slots: Vec::new(), depth: 0 };
+
+    let mut new_globals: HashMap<String, usize> = HashMap::new();
+    let global_count = reader.read_len()?;
+    for _ in 0..global_count {
+        let name = reader.read_string()?;
+        let slot = match globals.slot(&name) {
+            Some(slot) => slot,
+            None => {
+                let next = globals.len() + new_globals.len();
+                *new_globals.entry(name).or_insert(next)
+            },
+        };
+        reader.slots.push(slot);
+    }
+
+    let function = reader.read_function()?;
+    if reader.position != payload.len() {
+        return Err(String::from("Unexpected bytes after the script."));
+    }
+
+    let mut new_globals: Vec<(String, usize)> = new_globals.into_iter().collect();
+    new_globals.sort_by_key(|(_, slot)| *slot);
+    Ok((function, new_globals.into_iter().map(|(name, _)| name).collect()))
+}
+
+// 32 bit FNV-1a hash
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, byte| (hash ^ u32::from(*byte)).wrapping_mul(0x01000193))
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn write_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn write_len(&mut self, len: usize) -> Result<(), String> {
+        let len = u32::try_from(len).map_err(|_| String::from("Too much data to serialize."))?;
+        self.write_u32(len);
+        Ok(())
+    }
+
+    fn write_str(&mut self, string: &str) -> Result<(), String> {
+        self.write_len(string.len())?;
+        self.bytes.extend_from_slice(string.as_bytes());
+        Ok(())
+    }
+
+    // name, arity and upvalue count, then the chunk. constants come before the code so loading can rewrite it
+    fn write_function(&mut self, function: &ObjFunction) -> Result<(), String> {
+        match &function.name {
+            Some(name) => {
+                self.bytes.push(1);
+                self.write_str(name.as_str())?;
+            },
+            None => self.bytes.push(0),
+        }
+        self.write_len(function.arity)?;
+        self.write_len(function.upvalue_count)?;
+
+        let chunk = &widen_globals(&function.chunk)?;
+        self.write_len(chunk.constants.len())?;
+        for constant in &chunk.constants {
+            self.write_constant(constant)?;
+        }
+
+        self.write_len(chunk.code.len())?;
+        self.bytes.extend_from_slice(&chunk.code);
+
+        // already run-length encoded as line, count pairs
+        self.write_len(chunk.lines.len())?;
+        for line in &chunk.lines {
+            self.bytes.extend_from_slice(&line.to_le_bytes());
+        }
+
+        // span runs as line, column, start, end and count
+        self.write_len(chunk.spans.len())?;
+        for (span, count) in &chunk.spans {
+            self.bytes.extend_from_slice(&span.line.to_le_bytes());
+            self.bytes.extend_from_slice(&span.column.to_le_bytes());
+            self.write_len(span.start)?;
+            self.write_len(span.end)?;
+            self.write_len(*count)?;
+        }
+        Ok(())
+    }
+
+    fn write_constant(&mut self, constant: &Value) -> Result<(), String> {
+        match constant {
+            Value::ValVoid(()) => self.bytes.push(TAG_VOID),
+            Value::ValBool(false) => self.bytes.push(TAG_FALSE),
+            Value::ValBool(true) => self.bytes.push(TAG_TRUE),
+            Value::ValNumber(number) => {
+                self.bytes.push(TAG_NUMBER);
+                self.bytes.extend_from_slice(&number.to_le_bytes());
+            },
+            Value::ValObject(Object::ObjString(string)) => {
+                self.bytes.push(TAG_STRING);
+                self.write_str(string.as_str())?;
+            },
+            Value::ValObject(Object::ObjFunction(function)) => {
+                self.bytes.push(TAG_FUNCTION);
+                self.write_function(function)?;
+            },
+            _ => return Err(format!("Cannot serialize constant {}.", constant.read_value_str())),
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    strings: &'a mut Interner,
+    slots: Vec<usize>, // slot in the loading VM of every global slot in the file
+    depth: usize, // how many functions the function being read is declared in
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes: &'a [u8] = self.bytes;
+        let end = self.position.checked_add(len).filter(|end| *end <= bytes.len())
+            .ok_or_else(|| String::from("Bytecode ends unexpectedly."))?;
+        let read = &bytes[self.position..end];
+        self.position = end;
+        Ok(read)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_len(&mut self) -> Result<usize, String> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn read_i32(&mut self) -> Result<i32, String> {
+        let bytes = self.read_bytes(4)?;
+        Ok(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_string(&mut self) -> Result<String, String> {
+        let len = self.read_len()?;
+        let bytes = self.read_bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| String::from("Bytecode string is not valid UTF-8."))
+    }
+
+    fn read_function(&mut self) -> Result<ObjFunction, String> {
+        let name = match self.read_u8()? {
+            0 => None,
+            1 => {
+                let name = self.read_string()?;
+                Some(self.strings.intern(&name))
+            },
+            flag => return Err(format!("Invalid function name flag {}.", flag)),
+        };
+
+        let mut function = ObjFunction::new(name);
+        function.arity = self.read_len()?;
+        function.upvalue_count = self.read_len()?;
+
+        let mut chunk = Chunk::new();
+        let constant_count = self.read_len()?;
+        for _ in 0..constant_count {
+            let constant = self.read_constant()?;
+            chunk.constants.push(constant);
+        }
+
+        let code_len = self.read_len()?;
+        chunk.code = self.read_bytes(code_len)?.to_vec();
+        self.resolve_globals(&mut chunk)?;
+
+        // line, count pairs
+        let lines_len = self.read_len()?;
+        if lines_len % 2 != 0 {
+            return Err(String::from("Line table of a function is cut off."));
+        }
+        for _ in 0..lines_len {
+            let line = self.read_i32()?;
+            chunk.lines.push(line);
+        }
+        let line_runs = chunk.lines.chunks(2).map(|run| usize::try_from(run[1]).ok());
+        check_runs(line_runs, chunk.code.len(), "Line")?;
+
+        let spans_len = self.read_len()?;
+        for _ in 0..spans_len {
+            let line = self.read_i32()?;
+            let column = self.read_i32()?;
+            let span = Span { line, column, start: self.read_len()?, end: self.read_len()? };
+            let count = self.read_len()?;
+            chunk.spans.push((span, count));
+        }
+        check_runs(chunk.spans.iter().map(|(_, count)| Some(*count)), chunk.code.len(), "Span")?;
+
+        function.chunk = chunk;
+        Ok(function)
+    }
+
+    fn read_constant(&mut self) -> Result<Value, String> {
+        match self.read_u8()? {
+            TAG_VOID => Ok(Value::ValVoid(())),
+            TAG_FALSE => Ok(Value::ValBool(false)),
+            TAG_TRUE => Ok(Value::ValBool(true)),
+            TAG_NUMBER => {
+                let bytes = self.read_bytes(8)?;
+                let mut number = [0; 8];
+                number.copy_from_slice(bytes);
+                Ok(Value::ValNumber(f64::from_le_bytes(number)))
+            },
+            TAG_STRING => {
+                let string = self.read_string()?;
+                Ok(Value::ValObject(Object::ObjString(self.strings.intern(&string))))
+            },
+            TAG_FUNCTION => {
+                // a crafted file could nest deep enough to overflow the stack of the reader
+                if self.depth == MAX_NESTING {
+                    return Err(String::from("Functions are nested too deeply."));
+                }
+                self.depth += 1;
+                let function = self.read_function()?;
+                self.depth -= 1;
+                Ok(Value::ValObject(Object::ObjFunction(Rc::new(function))))
+            },
+            tag => Err(format!("Invalid constant tag {}.", tag)),
+        }
+    }
+
+    /*
+        Rewrite the operand of every global instruction from the slot in the file to the slot
+        in the loading VM. A short instruction can not take a slot above 255, rewriting it to
+        the long form would move every jump over it, so that is an error.
+    */
+    fn resolve_globals(&self, chunk: &mut Chunk) -> Result<(), String> {
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let (code, next) = decode(chunk, offset)?;
+
+            if code.is_global() {
+                let slot = read_operand(&chunk.code[offset + 1..offset + 1 + code.operand_len()]);
+                let resolved = *self.slots.get(slot)
+                    .ok_or_else(|| format!("Global slot {} at offset {} has no name.", slot, offset))?;
+
+                if code.is_long() {
+                    chunk.code[offset + 1] = (resolved >> 16) as u8;
+                    chunk.code[offset + 2] = (resolved >> 8) as u8;
+                    chunk.code[offset + 3] = resolved as u8;
+                } else {
+                    // only a file that was not written by write has short global instructions
+                    chunk.code[offset + 1] = u8::try_from(resolved)
+                        .map_err(|_| String::from("Too many globals to load the script into this VM."))?;
+                }
+            }
+
+            offset = next;
+        }
+        Ok(())
+    }
+}
+
+// the instruction at the offset and the offset of the one after it, past the upvalue pairs of a closure
+fn decode(chunk: &Chunk, offset: usize) -> Result<(OpCode, usize), String> {
+    let code = OpCode::from_byte(chunk.code[offset])
+        .ok_or_else(|| format!("Invalid opcode {} at offset {}.", chunk.code[offset], offset))?;
+    let mut next = offset + 1 + code.operand_len();
+    if next > chunk.code.len() {
+        return Err(format!("Operand of the instruction at offset {} is cut off.", offset));
+    }
+
+    if matches!(code, OpCode::OpClosure | OpCode::OpClosureLong) {
+        let index = read_operand(&chunk.code[offset + 1..next]);
+        match chunk.constants.get(index) {
+            Some(Value::ValObject(Object::ObjFunction(function))) => next += 2 * function.upvalue_count,
+            _ => return Err(format!("Closure at offset {} does not refer to a function.", offset)),
+        }
+    }
+    Ok((code, next))
+}
+
+/*
+    A copy of the chunk with every short global instruction in its long form.
+    Widening moves the code after it, so jumps are rewritten to land where they did, and every
+    byte keeps the line and span it was compiled from.
+*/
+fn widen_globals(chunk: &Chunk) -> Result<Chunk, String> {
+    let widens = |code: OpCode| code.is_global() && !code.is_long();
+
+    // where every instruction, and the end of the code, is in the widened code
+    let mut moved: Vec<Option<usize>> = vec![None; chunk.code.len() + 1];
+    let mut offset = 0;
+    let mut added = 0;
+    while offset < chunk.code.len() {
+        let (code, next) = decode(chunk, offset)?;
+        moved[offset] = Some(offset + added);
+        if widens(code) {
+            added += 2;
+        }
+        offset = next;
+    }
+    moved[chunk.code.len()] = Some(chunk.code.len() + added);
+
+    // distance between two instructions in the widened code, as the two byte operand of a jump
+    let distance = |from: usize, to: usize| -> Result<[u8; 2], String> {
+        let (from, to) = match (moved.get(from).copied().flatten(), moved.get(to).copied().flatten()) {
+            (Some(from), Some(to)) if from <= to => (from, to),
+            _ => return Err(String::from("Jump does not land on an instruction.")),
+        };
+        u16::try_from(to - from).map(u16::to_be_bytes)
+            .map_err(|_| String::from("Too much code to jump over once global instructions are widened."))
+    };
+
+    // line and span of every byte
+    let lines: Vec<i32> = chunk.lines.chunks(2)
+        .flat_map(|run| std::iter::repeat_n(run[0], usize::try_from(run[1]).unwrap_or(0)))
+        .collect();
+    let spans: Vec<Span> = chunk.spans.iter()
+        .flat_map(|(span, count)| std::iter::repeat_n(*span, *count))
+        .collect();
+
+    let mut widened = Chunk::new();
+    widened.constants = chunk.constants.clone();
+
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let (code, next) = decode(chunk, offset)?;
+        let mut bytes = chunk.code[offset..next].to_vec();
+
+        match code {
+            code if widens(code) => bytes = vec![long_global(code).into(), 0, 0, bytes[1]],
+            OpCode::OpJump | OpCode::OpJumpIfFalse => {
+                let target = next + read_operand(&bytes[1..3]);
+                bytes[1..3].copy_from_slice(&distance(next, target)?);
+            },
+            OpCode::OpLoop => {
+                let target = next.checked_sub(read_operand(&bytes[1..3]))
+                    .ok_or_else(|| String::from("Jump does not land on an instruction."))?;
+                bytes[1..3].copy_from_slice(&distance(target, next)?);
+            },
+            OpCode::OpForIter => {
+                let target = next + read_operand(&bytes[2..4]);
+                bytes[2..4].copy_from_slice(&distance(next, target)?);
+            },
+            _ => {},
+        }
+
+        // the bytes added by widening take the line and span of the operand they came from
+        let last = next - offset - 1;
+        for (index, byte) in bytes.into_iter().enumerate() {
+            let source = offset + index.min(last);
+            widened.write(byte, lines.get(source).copied().unwrap_or(0), spans.get(source).copied().unwrap_or_default());
+        }
+        offset = next;
+    }
+    Ok(widened)
+}
+
+fn long_global(code: OpCode) -> OpCode {
+    match code {
+        OpCode::OpDefineGlobal => OpCode::OpDefineGlobalLong,
+        OpCode::OpGetGlobal => OpCode::OpGetGlobalLong,
+        OpCode::OpSetGlobal => OpCode::OpSetGlobalLong,
+        OpCode::OpDefineConstGlobal => OpCode::OpDefineConstGlobalLong,
+        code => code,
+    }
+}
+
+/*
+    Check the lengths of a run-length encoded table, None for a length that does not fit.
+    Every run covers at least one byte and together they cover the code exactly.
+*/
+fn check_runs(runs: impl Iterator<Item = Option<usize>>, code_len: usize, table: &str) -> Result<(), String> {
+    let mut covered: usize = 0;
+    for run in runs {
+        covered = run.filter(|run| *run > 0).and_then(|run| covered.checked_add(run))
+            .ok_or_else(|| format!("{} table of a function has an invalid run length.", table))?;
+    }
+
+    match covered == code_len {
+        true => Ok(()),
+        false => Err(format!("{} table of a function does not cover its code.", table)),
+    }
+}
+
+// a one or three byte operand, high byte first
+fn read_operand(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |operand, byte| (operand << 8) | usize::from(*byte))
+}
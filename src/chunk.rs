@@ -6,9 +6,13 @@
 */
 //use crate::value;
 use crate::value::Value;
+use crate::object::{Object, ObjString};
+use crate::span::Span;
+use std::collections::HashMap;
 
 // OpCode is an enum that represents the different operation codes
 // each OpCode corresponds to a specific operation that the virtual machine can perform
+#[allow(clippy::enum_variant_names)]
 #[derive(Clone, Copy, Debug)]
 pub enum OpCode { 
     OpConstant, // load a constant value onto the stack
@@ -35,6 +39,42 @@ pub enum OpCode {
     OpJumpIfFalse,
     OpJump,
     OpLoop,
+    OpCall, // operand is the number of arguments passed to the callee
+    OpClosure, // operand is the function constant, followed by an (is_local, index) pair per upvalue
+    OpGetUpvalue,
+    OpSetUpvalue,
+    OpCloseUpvalue,
+    OpClass, // operand is the constant holding the class name
+    OpGetProperty,
+    OpSetProperty,
+    OpMethod,
+    OpInvoke, // operands are the method name constant and the number of arguments
+    OpInherit,
+    OpGetSuper,
+    OpSuperInvoke, // same operands as OpInvoke, the superclass is on top of the stack
+    OpBuildList, // operand is the number of elements on the stack
+    OpGetIndex,
+    OpSetIndex,
+    OpBuildMap, // operand is the number of key value pairs on the stack
+    OpToString,
+    OpDefineConstGlobal, // like OpDefineGlobal, but the global can not be assigned or redefined afterwards
+    // long forms take a three byte constant index, high byte first, for chunks with more than 256 constants
+    OpConstantLong,
+    OpDefineGlobalLong,
+    OpGetGlobalLong,
+    OpSetGlobalLong,
+    OpDefineConstGlobalLong,
+    OpClosureLong,
+    OpClassLong,
+    OpGetPropertyLong,
+    OpSetPropertyLong,
+    OpMethodLong,
+    OpInvokeLong,
+    OpGetSuperLong,
+    OpSuperInvokeLong,
+    OpPopN, // operand is the number of values to pop
+    OpRange, // operand is 1 for an inclusive range
+    OpForIter, // operands are the slot of the iterated value and a two byte jump taken when it is exhausted
 }
 
 // implement From trait for OpCode to convert OpCode to u8 and vice versa
@@ -46,36 +86,120 @@ impl From<OpCode> for u8 {
 
 impl From<u8> for OpCode {
     fn from(index: u8) -> OpCode {
+        match OpCode::from_byte(index) {
+            Some(code) => code,
+            None => panic!("Error. Invalid OpCode code"),
+        }
+    }
+}
+
+impl OpCode {
+    // the opcode with the given code, None for bytes that are not an opcode
+    pub fn from_byte(index: u8) -> Option<OpCode> {
         match index {
-            0 => OpCode::OpConstant,
-            1 => OpCode::OpAdd,
-            2 => OpCode::OpSubtract,
-            3 => OpCode::OpMultiply,
-            4 => OpCode::OpDivide,
-            5 => OpCode::OpNegate,
-            6 => OpCode::OpReturn,
-            7 => OpCode::OpVoid,
-            8 => OpCode::OpTrue,
-            9 => OpCode::OpFalse,
-            10 => OpCode::OpNot,
-            11 => OpCode::OpEqual,
-            12 => OpCode::OpGreater,
-            13 => OpCode::OpLess,
-            14 => OpCode::OpEmit,
-            15 => OpCode::OpPop,
-            16 => OpCode::OpDefineGlobal,
-            17 => OpCode::OpGetGlobal,
-            18 => OpCode::OpSetGlobal,
-            19 => OpCode::OpGetLocal,
-            20 => OpCode::OpSetLocal,
-            21 => OpCode::OpJumpIfFalse,
-            22 => OpCode::OpJump,
-            23 => OpCode::OpLoop,
-            _ => panic!("Error. Invalid OpCode code")
+            0 => Some(OpCode::OpConstant),
+            1 => Some(OpCode::OpAdd),
+            2 => Some(OpCode::OpSubtract),
+            3 => Some(OpCode::OpMultiply),
+            4 => Some(OpCode::OpDivide),
+            5 => Some(OpCode::OpNegate),
+            6 => Some(OpCode::OpReturn),
+            7 => Some(OpCode::OpVoid),
+            8 => Some(OpCode::OpTrue),
+            9 => Some(OpCode::OpFalse),
+            10 => Some(OpCode::OpNot),
+            11 => Some(OpCode::OpEqual),
+            12 => Some(OpCode::OpGreater),
+            13 => Some(OpCode::OpLess),
+            14 => Some(OpCode::OpEmit),
+            15 => Some(OpCode::OpPop),
+            16 => Some(OpCode::OpDefineGlobal),
+            17 => Some(OpCode::OpGetGlobal),
+            18 => Some(OpCode::OpSetGlobal),
+            19 => Some(OpCode::OpGetLocal),
+            20 => Some(OpCode::OpSetLocal),
+            21 => Some(OpCode::OpJumpIfFalse),
+            22 => Some(OpCode::OpJump),
+            23 => Some(OpCode::OpLoop),
+            24 => Some(OpCode::OpCall),
+            25 => Some(OpCode::OpClosure),
+            26 => Some(OpCode::OpGetUpvalue),
+            27 => Some(OpCode::OpSetUpvalue),
+            28 => Some(OpCode::OpCloseUpvalue),
+            29 => Some(OpCode::OpClass),
+            30 => Some(OpCode::OpGetProperty),
+            31 => Some(OpCode::OpSetProperty),
+            32 => Some(OpCode::OpMethod),
+            33 => Some(OpCode::OpInvoke),
+            34 => Some(OpCode::OpInherit),
+            35 => Some(OpCode::OpGetSuper),
+            36 => Some(OpCode::OpSuperInvoke),
+            37 => Some(OpCode::OpBuildList),
+            38 => Some(OpCode::OpGetIndex),
+            39 => Some(OpCode::OpSetIndex),
+            40 => Some(OpCode::OpBuildMap),
+            41 => Some(OpCode::OpToString),
+            42 => Some(OpCode::OpDefineConstGlobal),
+            43 => Some(OpCode::OpConstantLong),
+            44 => Some(OpCode::OpDefineGlobalLong),
+            45 => Some(OpCode::OpGetGlobalLong),
+            46 => Some(OpCode::OpSetGlobalLong),
+            47 => Some(OpCode::OpDefineConstGlobalLong),
+            48 => Some(OpCode::OpClosureLong),
+            49 => Some(OpCode::OpClassLong),
+            50 => Some(OpCode::OpGetPropertyLong),
+            51 => Some(OpCode::OpSetPropertyLong),
+            52 => Some(OpCode::OpMethodLong),
+            53 => Some(OpCode::OpInvokeLong),
+            54 => Some(OpCode::OpGetSuperLong),
+            55 => Some(OpCode::OpSuperInvokeLong),
+            56 => Some(OpCode::OpPopN),
+            57 => Some(OpCode::OpRange),
+            58 => Some(OpCode::OpForIter),
+            _ => None,
+        }
+    }
+
+    // whether the constant operand of the instruction is three bytes instead of one
+    pub fn is_long(self) -> bool {
+        matches!(self,
+            OpCode::OpConstantLong | OpCode::OpDefineGlobalLong | OpCode::OpGetGlobalLong | OpCode::OpSetGlobalLong
+            | OpCode::OpDefineConstGlobalLong | OpCode::OpClosureLong | OpCode::OpClassLong | OpCode::OpGetPropertyLong
+            | OpCode::OpSetPropertyLong | OpCode::OpMethodLong | OpCode::OpInvokeLong | OpCode::OpGetSuperLong
+            | OpCode::OpSuperInvokeLong)
+    }
+
+    // whether the operand of the instruction is a global slot instead of a constant index
+    pub fn is_global(self) -> bool {
+        matches!(self,
+            OpCode::OpDefineGlobal | OpCode::OpGetGlobal | OpCode::OpSetGlobal | OpCode::OpDefineConstGlobal
+            | OpCode::OpDefineGlobalLong | OpCode::OpGetGlobalLong | OpCode::OpSetGlobalLong | OpCode::OpDefineConstGlobalLong)
+    }
+
+    /*
+        Number of operand bytes following the opcode.
+        OpClosure and OpClosureLong are followed by another two bytes for every upvalue of their function,
+        those are not counted.
+    */
+    pub fn operand_len(self) -> usize {
+        match self {
+            OpCode::OpAdd | OpCode::OpSubtract | OpCode::OpMultiply | OpCode::OpDivide | OpCode::OpNegate
+            | OpCode::OpReturn | OpCode::OpVoid | OpCode::OpTrue | OpCode::OpFalse | OpCode::OpNot
+            | OpCode::OpEqual | OpCode::OpGreater | OpCode::OpLess | OpCode::OpEmit | OpCode::OpPop
+            | OpCode::OpCloseUpvalue | OpCode::OpInherit | OpCode::OpGetIndex | OpCode::OpSetIndex
+            | OpCode::OpToString => 0,
+            OpCode::OpJumpIfFalse | OpCode::OpJump | OpCode::OpLoop | OpCode::OpInvoke | OpCode::OpSuperInvoke => 2,
+            OpCode::OpForIter => 3,
+            OpCode::OpInvokeLong | OpCode::OpSuperInvokeLong => 4,
+            _ if self.is_long() => 3,
+            _ => 1,
         }
     }
 }
 
+// number of constants a chunk can hold, the most a three byte operand can address
+pub const MAX_CONSTANTS: usize = 1 << 24;
+
 // access the chunk's capacity and count using vector's .capacity() and .len()
 #[derive(Debug)]
 // Chunk is a struct that represents a chunk of bytecode
@@ -85,11 +209,34 @@ pub struct Chunk {
     pub code: Vec<u8>, // sequence of OpCodes stored as u8
     pub constants: Vec<Value>,
     pub lines: Vec<i32>,
+    pub spans: Vec<(Span, usize)>, // span of the token each byte was compiled from, run-length encoded like lines
+    constant_indices: HashMap<ConstantKey, usize>, // where each number and string already is in constants
+}
+
+/*
+    Key used to find a constant that is already in the chunk.
+    Numbers are keyed by their bits so 0 and -0 keep separate slots.
+    Other constants, like functions, are never shared.
+*/
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    String(ObjString),
+}
+
+impl ConstantKey {
+    fn from_value(value: &Value) -> Option<ConstantKey> {
+        match value {
+            Value::ValNumber(number) => Some(ConstantKey::Number(number.to_bits())),
+            Value::ValObject(Object::ObjString(string)) => Some(ConstantKey::String(string.clone())),
+            _ => None,
+        }
+    }
 }
 
 impl Chunk {
     pub fn new() -> Chunk{
-        Chunk {code: Vec::new(), constants: Vec::new(), lines: Vec::new()}
+        Chunk {code: Vec::new(), constants: Vec::new(), lines: Vec::new(), spans: Vec::new(), constant_indices: HashMap::new()}
     }
 
     // pub fn write(&mut self, byte: u8, line: i32) {
@@ -100,12 +247,17 @@ impl Chunk {
     /*
         compressed form of write line.
 
-        takes a chunk, a byte (the opcode converted to u8), and a line number
+        takes a chunk, a byte (the opcode converted to u8), a line number and the span of the token it was compiled from
     */
-    pub fn write(&mut self, byte: u8, line: i32) {
+    pub fn write(&mut self, byte: u8, line: i32, span: Span) {
 
         self.code.push(byte);
 
+        match self.spans.last_mut() {
+            Some((last, count)) if *last == span => *count += 1,
+            _ => self.spans.push((span, 1)),
+        }
+
         let len = self.lines.len();
 
         if len == 0 {
@@ -132,16 +284,17 @@ impl Chunk {
     }
 
     pub fn get_line(&self, instruction_index: usize) -> i32 {
-        let mut current_index = 0;
+        let mut current_index: usize = 0;
         let mut current_line = 0;
 
         for chunk in self.lines.chunks(2){
             let line = chunk[0];
-            let run_length = chunk[1];
+            let run_length = usize::try_from(chunk[1]).unwrap_or(0);
 
-            let next_index = current_index + run_length ;
+            // saturating, a loaded table could add up past usize
+            let next_index = current_index.saturating_add(run_length);
 
-            if (instruction_index as i32) < next_index {
+            if instruction_index < next_index {
                 current_line = line;
                 break;
             }
@@ -152,10 +305,32 @@ impl Chunk {
         current_line
 
     }
+    // span of the token the byte at the offset was compiled from
+    pub fn get_span(&self, instruction_index: usize) -> Span {
+        let mut current_index: usize = 0;
+        for (span, run_length) in &self.spans {
+            current_index = current_index.saturating_add(*run_length);
+            if instruction_index < current_index {
+                return *span;
+            }
+        }
+        Span::default()
+    }
+
     // add_constant returns usize
+    // a number or string that is already in the chunk is not added again, its existing index is returned
     pub fn add_constant(&mut self, value: Value) -> usize {
+        let key = ConstantKey::from_value(&value);
+        if let Some(index) = key.as_ref().and_then(|key| self.constant_indices.get(key)) {
+            return *index;
+        }
+
         self.constants.push(value);
-        self.constants.len() - 1
+        let index = self.constants.len() - 1;
+        if let Some(key) = key {
+            self.constant_indices.insert(key, index);
+        }
+        index
     }
 
     pub fn get_constant(&self, index: usize) -> &Value {
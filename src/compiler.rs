@@ -1,7 +1,12 @@
-use crate::{scanner::{Token, TokenType, Scanner}, chunk::{Chunk, OpCode}, value::Value, object::{Object, ObjString}};
+use crate::{scanner::{Token, TokenType, Scanner}, span::Span, diagnostic::{Diagnostic, Severity}, chunk::{Chunk, OpCode, MAX_CONSTANTS}, value::Value, object::{Object, ObjString, ObjFunction, Interner}, globals::{GlobalNames, MAX_GLOBALS}};
+use std::collections::HashMap;
+use std::rc::Rc;
 use std::str;
 use log::{trace};
 
+// number of locals a function can have, local slots are one byte operands
+const MAX_LOCALS: usize = 256;
+
 #[repr(u8)]
 #[derive(Copy, Clone, PartialEq, PartialOrd, Debug)]
 enum Precedence {
@@ -11,6 +16,7 @@ enum Precedence {
     And,
     Equality,
     Comparison,
+    Range,
     Term,
     Factor,
     Unary,
@@ -27,12 +33,13 @@ impl From<Precedence> for u8 {
 impl From<u8> for Precedence {
     fn from(precedence: u8) -> Precedence {
         match precedence {
-            1 => Precedence::None,
-            2 => Precedence::Assignment,
-            3 => Precedence::Or,
-            4 => Precedence::And,
-            5 => Precedence::Equality,
-            6 => Precedence::Comparison,
+            0 => Precedence::None,
+            1 => Precedence::Assignment,
+            2 => Precedence::Or,
+            3 => Precedence::And,
+            4 => Precedence::Equality,
+            5 => Precedence::Comparison,
+            6 => Precedence::Range,
             7 => Precedence::Term,
             8 => Precedence::Factor,
             9 => Precedence::Unary,
@@ -55,16 +62,16 @@ struct ParseRule {
 struct Parser<'a> {
     current: Token<'a>,
     previous: Token<'a>,
-    had_error: bool,
     panic_mode: bool,
+    diagnostics: Vec<Diagnostic>, // the first error of every statement and the warnings, in source order
 }
 
 impl<'a> Parser<'a> {
     pub fn new() -> Self {
-        Parser { current: Token::new() , previous: Token::new(), had_error: false, panic_mode: false}
+        Parser { current: Token::new() , previous: Token::new(), panic_mode: false, diagnostics: Vec::new() }
     }
 
-    pub fn to_string(&self) -> String {
+    pub fn describe(&self) -> String {
         format!("Current token: {}, previous token: {}", self.current.to_lexeme(), self.previous.to_lexeme())
     }
 }
@@ -73,23 +80,39 @@ impl<'a> Parser<'a> {
 struct Local<'a> {
     name: Token<'a>,
     depth: i32, // number of blocks surrounding the variable i.e to remember the scope where it is valid
+    is_captured: bool, // true if a closure captures this local, it has to be closed instead of popped
+    is_const: bool,
+    inline_value: Option<Value>, // literal initializer of a const, emitted in place of reading the local
 }
 
 impl <'a> Local <'a> {
     pub fn new(name: Token<'a>, depth: i32) -> Self {
-        Local { name: name, depth: depth }
+        Local { name, depth, is_captured: false, is_const: false, inline_value: None }
     }
 }
 
+/*
+    A variable from an enclosing function that the current function refers to.
+
+    if is_local is true, index is a local slot of the immediately enclosing function.
+    otherwise it is an index into the enclosing function's own upvalues.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Upvalue {
+    index: u8,
+    is_local: bool,
+}
+
 #[derive(Debug)]
 struct Scope <'a>{
     locals: Vec<Local<'a>>,
+    upvalues: Vec<Upvalue>,
     scope_depth: i32, // numberof blocks surrounding the current bit of code we're compiling
 }
 
 impl <'a> Scope <'a>{
     pub fn new() -> Self {
-        Self { locals: Vec::new(), scope_depth: 0}
+        Self { locals: Vec::new(), upvalues: Vec::new(), scope_depth: 0}
     }    
 
 
@@ -99,17 +122,152 @@ impl <'a> Scope <'a>{
     }
 }
 
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+/*
+    State for a single function being compiled.
+
+    Function declarations can be nested, so the compiler keeps a stack of these.
+    The innermost function is always the last one.
+*/
+#[derive(Debug)]
+struct FunctionState<'a> {
+    function: ObjFunction,
+    function_type: FunctionType,
+    scope: Scope<'a>,
+    last_pop: Option<usize>, // offset of the last OpPop or OpPopN emitted, see emit_pop
+    jump_target: Option<usize>, // offset of the last place a jump was made to land on
+    loops: Vec<LoopState>, // loops enclosing the code being compiled, innermost last
+    result_pop: Option<usize>, // offset of the OpPop after the last top level expression statement of the script
+}
+
+// loop whose body is being compiled, used by 'break' and 'continue'
+#[derive(Debug)]
+struct LoopState {
+    start: usize, // where 'continue' jumps back to
+    scope_depth: i32, // locals deeper than this belong to the body and are discarded when leaving it early
+    break_jumps: Vec<usize>, // jumps out of the loop, patched once the end of the loop is known
+}
+
+/*
+    Text of a String or Interpolation token without its delimiters.
+    The token may start with the opening quote and ends with either the closing quote or '${'.
+*/
+fn string_segment<'a>(token: Token<'a>) -> &'a str {
+    let text = token.to_lexeme();
+    let text = text.strip_prefix('"').unwrap_or(text);
+    match text.strip_suffix("${") {
+        Some(text) => text,
+        None => text.strip_suffix('"').unwrap_or(text),
+    }
+}
+
+// reads the {XXXX} part of a \u{XXXX} escape
+fn decode_unicode_escape(chars: &mut std::str::Chars) -> Option<char> {
+    if chars.next() != Some('{') {
+        return None;
+    }
+
+    let mut digits = String::new();
+    loop {
+        match chars.next()? {
+            '}' => break,
+            c if c.is_ascii_hexdigit() && digits.len() < 6 => digits.push(c),
+            _ => return None,
+        }
+    }
+
+    if digits.is_empty() {
+        return None;
+    }
+
+    char::from_u32(u32::from_str_radix(&digits, 16).ok()?)
+}
+
+/*
+    Remove the indentation shared by every line of a multi-line string.
+
+    A line break right after the opening quotes is dropped, and so is the last line
+    if it only holds the indentation of the closing quotes. That line still counts
+    towards the common indentation, so the closing quotes decide how far text is dedented.
+    Blank lines do not count.
+*/
+fn dedent(text: &str) -> String {
+    let text = text.strip_prefix("\r\n").or_else(|| text.strip_prefix('\n')).unwrap_or(text);
+    let mut lines: Vec<&str> = text.lines().collect();
+
+    let closing_indent = match text.rsplit_once('\n') {
+        Some((_, last)) if last.trim().is_empty() => {
+            // lines leaves out an empty last line, when the closing quotes are at the start of it
+            if lines.last().is_some_and(|line| line.as_ptr() == last.as_ptr()) {
+                lines.pop();
+            }
+            Some(last.len())
+        },
+        _ => None,
+    };
+
+    let indent = lines.iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .chain(closing_indent)
+        .min()
+        .unwrap_or(0);
+
+    let dedented: Vec<&str> = lines.iter()
+        .map(|line| if line.len() >= indent { &line[indent..] } else { "" })
+        .collect();
+    dedented.join("\n")
+}
+
+// token for a name the compiler refers to without it appearing in the source
+fn synthetic_token(text: &'static str) -> Token<'static> {
+    Token { token_type: TokenType::Identifier, lexeme: text.as_bytes(), line: 0, span: Span::default() }
+}
+
+impl <'a> FunctionState<'a> {
+    pub fn new(function_type: FunctionType, name: Option<ObjString>) -> Self {
+        let mut scope = Scope::new();
+        // slot zero of every call frame holds the function being called.
+        // for methods it holds the receiver instead, so it is named 'this'
+        let slot_zero = match function_type {
+            FunctionType::Method | FunctionType::Initializer => synthetic_token("this"),
+            FunctionType::Function | FunctionType::Script => Token::new(),
+        };
+        scope.locals.push(Local::new(slot_zero, 0));
+
+        FunctionState { function: ObjFunction::new(name), function_type, scope, last_pop: None, jump_target: None, loops: Vec::new(), result_pop: None }
+    }
+}
+
+// class whose body is being compiled, used to check that 'this' and 'super' are only used inside methods
+#[derive(Debug)]
+struct ClassState {
+    has_superclass: bool,
+}
+
 #[derive(Debug)]
 pub struct Compiler<'a> {
-    chunk: &'a mut Chunk,
     parser: Parser<'a>,
     scanner: Scanner<'a>,
     rules: Vec<ParseRule>,
-    scope: Scope<'a>,
+    functions: Vec<FunctionState<'a>>,
+    classes: Vec<ClassState>,
+    // const globals declared so far in this source, with their literal initializer if they have one.
+    // consts from earlier REPL lines are not known here, the VM checks those at runtime
+    global_consts: HashMap<&'a [u8], Option<Value>>,
+    strings: &'a mut Interner, // shared with the VM so string constants are interned with its runtime strings
+    globals: &'a mut GlobalNames, // owned by the VM, global variables are compiled to their slot in it
 }
 
 impl <'a> Compiler<'a> {
-    pub fn new(chunk: &'a mut Chunk, source: &'a str) -> Self {
+    pub fn new(source: &'a str, strings: &'a mut Interner, globals: &'a mut GlobalNames) -> Self {
         let mut rules = vec![
             ParseRule {
                 prefix: None,
@@ -120,8 +278,8 @@ impl <'a> Compiler<'a> {
 
         rules[TokenType::LeftParen as usize] = ParseRule {
             prefix: Some(|compiler, _can_assign| compiler.grouping()),
-            infix: None,
-            precedence: Precedence::None,
+            infix: Some(|compiler, _can_assign| compiler.call()),
+            precedence: Precedence::Call,
         };
 
         rules[TokenType::RightParen as usize] = ParseRule {
@@ -131,7 +289,7 @@ impl <'a> Compiler<'a> {
         };
     
         rules[TokenType::LeftBrace as usize] = ParseRule {
-            prefix: None,
+            prefix: Some(|compiler, _can_assign| compiler.map()),
             infix: None,
             precedence: Precedence::None,
         };
@@ -142,6 +300,30 @@ impl <'a> Compiler<'a> {
             precedence: Precedence::None,
         };
     
+        rules[TokenType::LeftBracket as usize] = ParseRule {
+            prefix: Some(|compiler, _can_assign| compiler.list()),
+            infix: Some(|compiler, can_assign| compiler.index(can_assign)),
+            precedence: Precedence::Call,
+        };
+
+        rules[TokenType::RightBracket as usize] = ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        };
+
+        rules[TokenType::DotDot as usize] = ParseRule {
+            prefix: None,
+            infix: Some(|compiler, _can_assign| compiler.range()),
+            precedence: Precedence::Range,
+        };
+
+        rules[TokenType::DotDotEqual as usize] = ParseRule {
+            prefix: None,
+            infix: Some(|compiler, _can_assign| compiler.range()),
+            precedence: Precedence::Range,
+        };
+
         rules[TokenType::Comma as usize] = ParseRule {
             prefix: None,
             infix: None,
@@ -150,8 +332,8 @@ impl <'a> Compiler<'a> {
     
         rules[TokenType::Dot as usize] = ParseRule {
             prefix: None,
-            infix: None,
-            precedence: Precedence::None,
+            infix: Some(|compiler, can_assign| compiler.dot(can_assign)),
+            precedence: Precedence::Call,
         };
     
         rules[TokenType::Minus as usize] = ParseRule {
@@ -244,6 +426,24 @@ impl <'a> Compiler<'a> {
             precedence: Precedence::None,
         };
     
+        rules[TokenType::Interpolation as usize] = ParseRule {
+            prefix: Some(|compiler, _can_assign| compiler.interpolation()),
+            infix: None,
+            precedence: Precedence::None,
+        };
+
+        rules[TokenType::RawString as usize] = ParseRule {
+            prefix: Some(|compiler, _can_assign| compiler.string()),
+            infix: None,
+            precedence: Precedence::None,
+        };
+
+        rules[TokenType::MultilineString as usize] = ParseRule {
+            prefix: Some(|compiler, _can_assign| compiler.string()),
+            infix: None,
+            precedence: Precedence::None,
+        };
+
         rules[TokenType::Number as usize] = ParseRule {
             prefix: Some(|compiler, _can_assign| compiler.number()),
             infix: None,
@@ -322,6 +522,24 @@ impl <'a> Compiler<'a> {
             precedence: Precedence::None,
         };
     
+        rules[TokenType::Class as usize] = ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        };
+
+        rules[TokenType::This as usize] = ParseRule {
+            prefix: Some(|compiler, _can_assign| compiler.this()),
+            infix: None,
+            precedence: Precedence::None,
+        };
+
+        rules[TokenType::Super as usize] = ParseRule {
+            prefix: Some(|compiler, _can_assign| compiler.super_()),
+            infix: None,
+            precedence: Precedence::None,
+        };
+
         rules[TokenType::Error as usize] = ParseRule {
             prefix: None,
             infix: None,
@@ -334,7 +552,37 @@ impl <'a> Compiler<'a> {
             precedence: Precedence::None,
         };
 
-        Compiler { chunk: chunk, parser: Parser::new(), scanner: Scanner::new(source), rules: rules, scope: Scope::new()}
+        Compiler {
+            parser: Parser::new(),
+            scanner: Scanner::new(source),
+            rules,
+            functions: vec![FunctionState::new(FunctionType::Script, None)],
+            classes: Vec::new(),
+            global_consts: HashMap::new(),
+            strings,
+            globals,
+        }
+    }
+
+    fn current(&self) -> &FunctionState<'a> {
+        self.functions.last().unwrap()
+    }
+
+    fn current_mut(&mut self) -> &mut FunctionState<'a> {
+        self.functions.last_mut().unwrap()
+    }
+
+    // chunk of the function currently being compiled
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.current_mut().function.chunk
+    }
+
+    fn scope(&self) -> &Scope<'a> {
+        &self.current().scope
+    }
+
+    fn scope_mut(&mut self) -> &mut Scope<'a> {
+        &mut self.current_mut().scope
     }
 
     
@@ -344,18 +592,25 @@ impl <'a> Compiler<'a> {
     }
   
     fn begin_scope(&mut self) {
-        self.scope.scope_depth += 1;
+        self.scope_mut().scope_depth += 1;
     }
 
     
     fn end_scope(&mut self) {
-        self.scope.scope_depth -= 1;
+        self.scope_mut().scope_depth -= 1;
+
+        while let Some(last) = self.scope().locals.last() {
+            if last.depth <= self.scope().scope_depth {
+                break;
+            }
 
-        while let Some(last) = self.scope.locals.last() {
-            if last.depth > self.scope.scope_depth {
-                self.emit_byte(OpCode::OpPop.into());
-                self.scope.locals.pop();
+            // captured locals are moved off the stack into their upvalue
+            if last.is_captured {
+                self.emit_byte(OpCode::OpCloseUpvalue.into());
+            } else {
+                self.emit_pop();
             }
+            self.scope_mut().locals.pop();
         }
     }
 
@@ -370,7 +625,7 @@ impl <'a> Compiler<'a> {
         loop {
             // scan the next token (scan more source code until find a valid lexeme and convert to token) and store in current
             self.parser.current = self.scanner.scan_token();
-            trace!("advance, {}", self.parser.to_string());
+            trace!("advance, {}", self.parser.describe());
             if self.parser.current.token_type != TokenType::Error {
                 break;
             }
@@ -383,11 +638,13 @@ impl <'a> Compiler<'a> {
         if current token in parser is of type token_type, advance to next token.
     */
     
-    fn consume(&mut self, _token_type: TokenType, message: &str) {
-        match self.parser.current.token_type {
-            _token_type => self.advance(),
-            //_ => self.error_at_current(message),
-        };
+    fn consume(&mut self, token_type: TokenType, message: &str) {
+        if self.parser.current.token_type == token_type {
+            self.advance();
+            return;
+        }
+
+        self.error_at_current(message);
     }
 
     
@@ -409,7 +666,7 @@ impl <'a> Compiler<'a> {
     fn my_and(&mut self, _can_assign: bool) {
         let end_jump = self.emit_jump(OpCode::OpJumpIfFalse.into());
 
-        self.emit_byte(OpCode::OpPop.into());
+        self.emit_pop();
         self.parse_precedence(Precedence::And);
 
         self.patch_jump(end_jump);
@@ -421,7 +678,7 @@ impl <'a> Compiler<'a> {
         let end_jump = self.emit_jump(OpCode::OpJump.into());
 
         self.patch_jump(else_jump);
-        self.emit_byte(OpCode::OpPop.into());
+        self.emit_pop();
 
         self.parse_precedence(Precedence::Or);
         self.patch_jump(end_jump);
@@ -441,7 +698,7 @@ impl <'a> Compiler<'a> {
     
     fn var_declaration(&mut self) {
         trace!("var_declaration");
-        let global: u8 = self.parse_variable("Expecting variable name.");
+        let global = self.parse_variable("Expecting variable name.");
 
         if self.is_match(TokenType::Equal) {
             self.expression();
@@ -455,11 +712,260 @@ impl <'a> Compiler<'a> {
     }
 
     
+    /*
+        Executed if class token is found.
+
+        The class is bound to its name first, then it is loaded back onto the stack
+        so every method can be attached to it.
+
+        A superclass is stored in a local named 'super' in a scope wrapping the class body,
+        so methods can capture it like any other variable.
+    */
+    fn class_declaration(&mut self) {
+        trace!("class_declaration");
+        self.consume(TokenType::Identifier, "Expecting class name.");
+        let class_name = self.parser.previous;
+        let name_constant = self.identifier_constant(class_name);
+        self.declare_variable();
+        let global = match self.scope().scope_depth {
+            0 => self.global_slot(class_name),
+            _ => 0,
+        };
+
+        self.emit_with_operand(OpCode::OpClass, OpCode::OpClassLong, name_constant);
+        self.define_variable(global);
+
+        self.classes.push(ClassState { has_superclass: false });
+
+        if self.is_match(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expecting superclass name.");
+            self.variable(false);
+
+            if class_name.lexeme == self.parser.previous.lexeme {
+                self.error("A class cannot inherit from itself.");
+            }
+
+            self.begin_scope();
+            self.add_local(synthetic_token("super"));
+            self.define_variable(0);
+
+            self.named_variable(class_name, false);
+            self.emit_byte(OpCode::OpInherit.into());
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        self.named_variable(class_name, false);
+        self.consume(TokenType::LeftBrace, "Expecting '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expecting '}' after class body.");
+        // pop the class
+        self.emit_pop();
+
+        if self.classes.last().unwrap().has_superclass {
+            self.end_scope();
+        }
+
+        self.classes.pop();
+    }
+
+    // a method is declared like a function, without the function keyword
+    fn method(&mut self) {
+        trace!("method");
+        self.consume(TokenType::Identifier, "Expecting method name.");
+        let constant = self.identifier_constant(self.parser.previous);
+
+        let function_type = match self.parser.previous.lexeme {
+            b"init" => FunctionType::Initializer,
+            _ => FunctionType::Method,
+        };
+        self.function(function_type);
+
+        self.emit_with_operand(OpCode::OpMethod, OpCode::OpMethodLong, constant);
+    }
+
+    /*
+        Executed if function token is found.
+
+        A function is bound to a variable like any other value, so the name is declared the same way.
+        The name is marked initialized before the body is compiled so the function can call itself.
+    */
+    fn fun_declaration(&mut self) {
+        trace!("fun_declaration");
+        let global = self.parse_variable("Expecting function name.");
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    /*
+        Compile the parameter list and body of a function into a new function object
+        and emit it as a constant in the enclosing chunk.
+    */
+    fn function(&mut self, function_type: FunctionType) {
+        trace!("function");
+        let name = self.strings.intern(self.parser.previous.to_lexeme());
+        self.functions.push(FunctionState::new(function_type, Some(name)));
+        // parameters are locals of the function body, there is no matching end_scope
+        // as the whole function state is thrown away once the body is compiled
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expecting '(' after function name.");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.current_mut().function.arity += 1;
+                if self.current().function.arity > 255 {
+                    self.error_at_current("Cannot have more than 255 parameters.");
+                }
+
+                let constant = self.parse_variable("Expecting parameter name.");
+                self.define_variable(constant);
+
+                if !self.is_match(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expecting ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expecting '{' before function body.");
+        self.block();
+
+        let upvalues = self.scope().upvalues.clone();
+        let function = self.end_compiler();
+
+        let constant = self.make_constant(Value::ValObject(Object::ObjFunction(Rc::new(function))));
+        self.emit_with_operand(OpCode::OpClosure, OpCode::OpClosureLong, constant);
+
+        // tell the VM where to capture each upvalue from when creating the closure
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local as u8, upvalue.index);
+        }
+    }
+
+    /*
+        Executed if return token is found.
+
+        A bare return returns void.
+    */
+    fn return_statement(&mut self) {
+        trace!("return statement");
+        if self.current().function_type == FunctionType::Script {
+            self.error("Cannot return from top-level code.");
+        }
+
+        if self.is_match(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.current().function_type == FunctionType::Initializer {
+                self.error_with_hint(self.parser.previous, "Cannot return a value from an initializer.", Some("an initializer always returns 'this', use a bare 'return;'."));
+            }
+
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expecting ';' after return value.");
+            self.emit_byte(OpCode::OpReturn.into());
+        }
+    }
+
+    /*
+        Executed if const token is found.
+
+        A const must be initialized and can not be assigned to afterwards.
+        If the initializer is a single literal, reads of the const compile to that literal.
+    */
+    fn const_declaration(&mut self) {
+        trace!("const_declaration");
+        self.consume(TokenType::Identifier, "Expecting constant name.");
+        let name = self.parser.previous;
+        // a rejected duplicate adds no local, the last one belongs to another variable then
+        let added_local = self.declare_variable();
+        let is_local = self.scope().scope_depth > 0;
+        let global = match is_local {
+            true => 0,
+            false => self.global_slot(name),
+        };
+
+        if added_local {
+            if let Some(local) = self.scope_mut().locals.last_mut() {
+                local.is_const = true;
+            }
+        }
+
+        self.consume(TokenType::Equal, "Constant must be initialized.");
+        let initializer_start = self.current_chunk().code.len();
+        self.expression();
+        let inline_value = self.literal_since(initializer_start);
+
+        self.consume(TokenType::Semicolon, "Expecting ';' after expression");
+
+        if added_local {
+            if let Some(local) = self.scope_mut().locals.last_mut() {
+                local.inline_value = inline_value;
+            }
+            self.mark_initialized();
+        } else if !is_local {
+            self.global_consts.insert(name.lexeme, inline_value);
+            self.emit_with_operand(OpCode::OpDefineConstGlobal, OpCode::OpDefineConstGlobalLong, global);
+        }
+    }
+
+    // if everything emitted from start onwards is a single literal, return its value
+    fn literal_since(&mut self, start: usize) -> Option<Value> {
+        let chunk = self.current_chunk();
+        let code = &chunk.code[start..];
+
+        match code {
+            [op] if *op == u8::from(OpCode::OpTrue) => Some(Value::ValBool(true)),
+            [op] if *op == u8::from(OpCode::OpFalse) => Some(Value::ValBool(false)),
+            [op] if *op == u8::from(OpCode::OpVoid) => Some(Value::ValVoid(())),
+            [op, index] if *op == u8::from(OpCode::OpConstant) => Some(chunk.constants[*index as usize].clone()),
+            [op, high, middle, low] if *op == u8::from(OpCode::OpConstantLong) => {
+                let index = (usize::from(*high) << 16) | (usize::from(*middle) << 8) | usize::from(*low);
+                Some(chunk.constants[index].clone())
+            },
+            _ => None,
+        }
+    }
+
+    /*
+        Find what the name refers to, innermost scope first, and say whether it is a const.
+        Returns None if it is not a const, otherwise the literal to inline if there is one.
+    */
+    fn lookup_const(&self, name: Token) -> Option<Option<Value>> {
+        for function in self.functions.iter().rev() {
+            if let Some(local) = function.scope.locals.iter().rev().find(|local| local.name.lexeme == name.lexeme) {
+                return match local.is_const {
+                    true => Some(local.inline_value.clone()),
+                    false => None,
+                };
+            }
+        }
+
+        self.global_consts.get(name.lexeme).cloned()
+    }
+
+    // emit the instruction that loads a literal value
+    fn emit_literal(&mut self, value: Value) {
+        match value {
+            Value::ValBool(true) => self.emit_byte(OpCode::OpTrue.into()),
+            Value::ValBool(false) => self.emit_byte(OpCode::OpFalse.into()),
+            Value::ValVoid(()) => self.emit_byte(OpCode::OpVoid.into()),
+            _ => self.emit_constant(value),
+        }
+    }
+
     fn expression_statement(&mut self) {
         trace!("expression statement");
         self.expression();
         self.consume(TokenType::Semicolon, "Expecting ';' after expression");
-        self.emit_byte(OpCode::OpPop.into());
+        self.emit_pop();
+
+        // remembered so the script can return the value instead, if nothing follows the statement
+        let end = self.current_chunk().code.len();
+        if self.current().function_type == FunctionType::Script && self.scope().scope_depth == 0
+            && self.current().last_pop == Some(end - 1) {
+            self.current_mut().result_pop = Some(end - 1);
+        }
     }
 
     /*
@@ -477,8 +983,14 @@ impl <'a> Compiler<'a> {
     
     fn declaration(&mut self) {
         trace!("declaration");
-        if self.is_match(TokenType::Create) {
+        if self.is_match(TokenType::Class) {
+            self.class_declaration();
+        } else if self.is_match(TokenType::Function) {
+            self.fun_declaration();
+        } else if self.is_match(TokenType::Create) {
             self.var_declaration();
+        } else if self.is_match(TokenType::Const) {
+            self.const_declaration();
         } else {
             self.statement();
         }
@@ -489,9 +1001,14 @@ impl <'a> Compiler<'a> {
     }
 
     
-    fn synchronize(&mut self) {
-        //hit compile error while parsing previous statement. start synchronizing
+    /*
+        Hit a compile error while parsing the previous statement, skip to where the next one starts.
 
+        That is after a ';' or at a keyword that begins a statement. Inside a block the closing '}'
+        is a boundary too, so the block still ends where it should and the error does not spill
+        into the code after it.
+    */
+    fn synchronize(&mut self) {
         self.parser.panic_mode = false;
 
         while self.parser.current.token_type != TokenType::Eof {
@@ -500,9 +1017,12 @@ impl <'a> Compiler<'a> {
             }
 
             match self.parser.current.token_type {
-                TokenType::Function | TokenType::Create | TokenType::While | TokenType::Emit | TokenType::Return => {
+                TokenType::Class | TokenType::Function | TokenType::Create | TokenType::Const | TokenType::If
+                | TokenType::While | TokenType::For | TokenType::Emit | TokenType::Return | TokenType::Break
+                | TokenType::Continue => {
                     return;
                 },
+                TokenType::RightBrace if self.scope().scope_depth > 0 => return,
                 _ => (),
             }
 
@@ -519,16 +1039,16 @@ impl <'a> Compiler<'a> {
 
         let then_jump = self.emit_jump(OpCode::OpJumpIfFalse.into()); //opcode has operand for how much to offset the ip
 
-        print!("then jump at {}", then_jump.clone());
+        trace!("then jump at {}", then_jump);
 
-        self.emit_byte(OpCode::OpPop.into());
+        self.emit_pop();
 
         self.statement();
         let else_jump = self.emit_jump(OpCode::OpJump.into());
 
-        print!("else jump at {}", else_jump.clone()); 
+        trace!("else jump at {}", else_jump);
         self.patch_jump(then_jump);
-        self.emit_byte(OpCode::OpPop.into());
+        self.emit_pop();
 
         if self.is_match(TokenType::Else) {
             self.statement();
@@ -539,31 +1059,32 @@ impl <'a> Compiler<'a> {
 
     
     fn emit_jump(&mut self, instruction: u8) -> usize {
-        trace!("emit_jump: instruction = {}", instruction.clone());
+        trace!("emit_jump: instruction = {}", instruction);
         self.emit_byte(instruction);
         //placeholder operands
         // using 2 bytes for jump offset
         self.emit_byte(0xff);
         self.emit_byte(0xff);
         //return the place where we are rn (barring the placeholder operands)
-        self.chunk.code.len() - 2
+        self.current_chunk().code.len() - 2
     }
 
     
     fn patch_jump(&mut self, offset: usize) {
-        let jump = self.chunk.code.len() - offset - 2;
+        self.mark_jump_target();
+        let jump = self.current_chunk().code.len() - offset - 2;
 
-        if jump as u16 > u16::MAX {
-            self.error_at_current("Too much code to jump over");
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over");
         }
         //println!("chunk before patch jump {:?}, index - {}", self.chunk.code, offset);
         //get high byte of the two bytes set aside for jump
         // jump >> 8 will isolate the high byte and & 0xff will ensure only 8 lsb are retained
-        if let Some(bytecode_offset) = self.chunk.code.get_mut(offset) {
+        if let Some(bytecode_offset) = self.current_chunk().code.get_mut(offset) {
             *bytecode_offset = ((jump >> 8) & 0xff) as u8;
         }
         //get low byte
-        if let Some(bytecode_offset) = self.chunk.code.get_mut(offset + 1) {
+        if let Some(bytecode_offset) = self.current_chunk().code.get_mut(offset + 1) {
             *bytecode_offset = (jump & 0xff) as u8;
         }
 
@@ -576,53 +1097,60 @@ impl <'a> Compiler<'a> {
     
     fn while_statement(&mut self) {
         trace!("while statement");
-        let loop_start = self.chunk.code.len(); //capture location of start of loop
+        let loop_start = self.mark_jump_target(); //capture location of start of loop
         self.consume(TokenType::LeftParen, "Expecting '(' after 'while'.");
         self.expression();
-        self.consume(TokenType::While, "Expecting ')' after condition.");
+        self.consume(TokenType::RightParen, "Expecting ')' after condition.");
 
         let exit_jump = self.emit_jump(OpCode::OpJumpIfFalse.into());
-        self.emit_byte(OpCode::OpPop.into());
+        self.emit_pop();
+        self.begin_loop(loop_start);
         self.statement();
         self.emit_loop(loop_start); //to jump backward
 
         self.patch_jump(exit_jump);
-        self.emit_byte(OpCode::OpPop.into());
+        self.emit_pop();
+        self.end_loop();
     }
 
     fn for_statement(&mut self) {
         trace!("for statement");
-        self.begin_scope();
         self.consume(TokenType::LeftParen, "Expecting '(' after 'for'.");
+        if self.check(TokenType::Identifier) && self.scanner.peek_token().token_type == TokenType::In {
+            self.for_in_statement();
+            return;
+        }
+
+        self.begin_scope();
         
-        if (self.is_match(TokenType::Semicolon)) {
+        if self.is_match(TokenType::Semicolon) {
             // no condition
-        } else if (self.is_match(TokenType::Create)){
+        } else if self.is_match(TokenType::Create) {
             self.var_declaration();
         } else {
             self.expression_statement();
         }
 
-        let mut loop_start = self.chunk.code.len();
+        let mut loop_start = self.mark_jump_target();
         
         let mut exit_jump = -1;
 
-        if (!self.is_match(TokenType::Semicolon)) {
+        if !self.is_match(TokenType::Semicolon) {
             self.expression();
             self.consume(TokenType::Semicolon, "Expecting ';' after loop condition.");
 
             //jump out of the loop if condition is false 
             exit_jump = self.emit_jump(OpCode::OpJumpIfFalse.into()) as isize;
-            self.emit_byte(OpCode::OpPop.into()); // pop condition
+            self.emit_pop(); // pop condition
 
         }
 
-        if (!self.is_match(TokenType::RightParen)) {
+        if !self.is_match(TokenType::RightParen) {
             let body_jump = self.emit_jump(OpCode::OpJump.into());
 
-            let increment_start = self.chunk.code.len();
+            let increment_start = self.mark_jump_target();
             self.expression();
-            self.emit_byte(OpCode::OpPop.into());
+            self.emit_pop();
 
             self.consume(TokenType::RightParen, "Expecting ')' after for clauses.");
 
@@ -632,17 +1160,137 @@ impl <'a> Compiler<'a> {
             self.patch_jump(body_jump);
         }
 
+        // 'continue' runs the increment clause before checking the condition again
+        self.begin_loop(loop_start);
         self.statement();
         self.emit_loop(loop_start);
 
         if exit_jump != -1 {
             self.patch_jump(exit_jump as usize);
-            self.emit_byte(OpCode::OpPop.into()); // pop condition
+            self.emit_pop(); // pop condition
+        }
+
+        self.end_loop();
+        self.end_scope();
+    }
+
+    /*
+        'for (x in iterable) body'
+        The iterable and the position reached in it are kept in two hidden locals while the loop runs.
+        OpForIter pushes the next element, which is the loop variable inside the body's scope,
+        and jumps past the loop once there are no elements left.
+    */
+    fn for_in_statement(&mut self) {
+        trace!("for in statement");
+        self.begin_scope();
+        self.consume(TokenType::Identifier, "Expecting loop variable name.");
+        let variable = self.parser.previous;
+        self.consume(TokenType::In, "Expecting 'in' after loop variable.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expecting ')' after loop iterable.");
+
+        let iterable_slot = self.scope().locals.len();
+        self.add_hidden_local("for iterable");
+        self.emit_constant(Value::ValNumber(0.0));
+        self.add_hidden_local("for position");
+
+        let loop_start = self.mark_jump_target();
+        self.emit_bytes(OpCode::OpForIter.into(), iterable_slot as u8);
+        self.emit_bytes(0xff, 0xff);
+        let exit_jump = self.current_chunk().code.len() - 2;
+
+        self.begin_loop(loop_start);
+        self.begin_scope();
+        if self.add_local(variable) {
+            self.mark_initialized();
         }
+        self.statement();
+        self.end_scope();
+        self.emit_loop(loop_start);
 
+        self.patch_jump(exit_jump);
+        self.end_loop();
         self.end_scope();
     }
 
+    // local the compiler keeps on the stack for itself, its name can not be written in the source
+    fn add_hidden_local(&mut self, name: &'static str) {
+        if self.add_local(synthetic_token(name)) {
+            self.mark_initialized();
+        }
+    }
+
+    // returns true if the local was added, a function that already has MAX_LOCALS of them is an error
+    fn add_local(&mut self, name: Token<'a>) -> bool {
+        if self.scope().locals.len() == MAX_LOCALS {
+            self.error("Too many local variables in function.");
+            return false;
+        }
+
+        self.scope_mut().add_local(name);
+        true
+    }
+
+    fn begin_loop(&mut self, start: usize) {
+        let scope_depth = self.scope().scope_depth;
+        self.current_mut().loops.push(LoopState { start, scope_depth, break_jumps: Vec::new() });
+    }
+
+    // every 'break' in the loop jumps to the code right after it
+    fn end_loop(&mut self) {
+        if let Some(loop_state) = self.current_mut().loops.pop() {
+            for jump in loop_state.break_jumps {
+                self.patch_jump(jump);
+            }
+        }
+    }
+
+    fn break_statement(&mut self) {
+        trace!("break statement");
+        match self.current().loops.last() {
+            Some(loop_state) => {
+                let scope_depth = loop_state.scope_depth;
+                self.discard_loop_locals(scope_depth);
+                let jump = self.emit_jump(OpCode::OpJump.into());
+                self.current_mut().loops.last_mut().unwrap().break_jumps.push(jump);
+            },
+            None => self.error("Cannot use 'break' outside of a loop."),
+        }
+        self.consume(TokenType::Semicolon, "Expecting ';' after 'break'.");
+    }
+
+    fn continue_statement(&mut self) {
+        trace!("continue statement");
+        match self.current().loops.last() {
+            Some(loop_state) => {
+                let (start, scope_depth) = (loop_state.start, loop_state.scope_depth);
+                self.discard_loop_locals(scope_depth);
+                self.emit_loop(start);
+            },
+            None => self.error("Cannot use 'continue' outside of a loop."),
+        }
+        self.consume(TokenType::Semicolon, "Expecting ';' after 'continue'.");
+    }
+
+    /*
+        Pop the locals of the loop body before jumping out of it.
+        They stay declared, the code after the jump still belongs to their scope.
+    */
+    fn discard_loop_locals(&mut self, scope_depth: i32) {
+        let captured: Vec<bool> = self.scope().locals.iter().rev()
+            .take_while(|local| local.depth > scope_depth)
+            .map(|local| local.is_captured)
+            .collect();
+
+        for is_captured in captured {
+            if is_captured {
+                self.emit_byte(OpCode::OpCloseUpvalue.into());
+            } else {
+                self.emit_pop();
+            }
+        }
+    }
+
     /*
         if not startinng valid keyword, then probably an expression statement
     */
@@ -657,6 +1305,12 @@ impl <'a> Compiler<'a> {
             self.while_statement();
         } else if self.is_match(TokenType::For) {
             self.for_statement();
+        } else if self.is_match(TokenType::Return) {
+            self.return_statement();
+        } else if self.is_match(TokenType::Break) {
+            self.break_statement();
+        } else if self.is_match(TokenType::Continue) {
+            self.continue_statement();
         } else if self.is_match(TokenType::LeftBrace){
             self.begin_scope();
             self.block();
@@ -667,10 +1321,26 @@ impl <'a> Compiler<'a> {
     }
 
     
+    /*
+        Code following a return, break or continue in the same block can never run,
+        it is compiled but warned about once.
+    */
     fn block(&mut self) {
         trace!("block");
+        let mut jumped_away: Option<Token> = None;
+        let mut warned = false;
         while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            if let (Some(keyword), false) = (jumped_away, warned) {
+                let message = format!("Unreachable code after '{}'.", keyword.to_lexeme());
+                self.warning_at(self.parser.current, &message);
+                warned = true;
+            }
+
+            let token = self.parser.current;
             self.declaration();
+            if matches!(token.token_type, TokenType::Return | TokenType::Break | TokenType::Continue) {
+                jumped_away = Some(token);
+            }
         }
 
         self.consume(TokenType::RightBrace, "Expecting '}' after block");
@@ -684,14 +1354,14 @@ impl <'a> Compiler<'a> {
         let rule = self.get_rule(self.parser.previous.token_type);
 
         if rule.is_none() {
-            eprintln!("Invalid token. Syntax Error");
+            self.error("Invalid token.");
             return;
         }
 
         let prefix_rule = rule.unwrap().prefix;
 
         if prefix_rule.is_none() {
-            eprintln!("Expecting expression");
+            self.error("Expecting expression.");
             return;
         }
 
@@ -705,7 +1375,7 @@ impl <'a> Compiler<'a> {
             self.advance();
             let rule = self.get_rule(self.parser.previous.token_type);
             if rule.is_none() {
-                eprintln!("Invalid token. Syntax Error");
+                self.error("Invalid token.");
                 return;
             }
             
@@ -721,20 +1391,13 @@ impl <'a> Compiler<'a> {
     }
 
     
-    fn identifier_constant(&mut self, name: Token) -> u8 {
-        trace!("identifier_constant: name = {:?}", name.clone());
+    fn identifier_constant(&mut self, name: Token) -> usize {
+        trace!("identifier_constant: name = {:?}", name);
 
         // global variables are looked up by name at runtime. so vm needs access to name. cannot put
         // whole string into bytecode so put in chunk's constant array and refer by index.
-        self.make_constant( 
-            Value::ValObject(
-                Object::ObjString(
-                    ObjString::from_str(
-                        str::from_utf8(name.lexeme).unwrap()
-                    )
-                )
-            )
-        )
+        let name = self.strings.intern(str::from_utf8(name.lexeme).unwrap());
+        self.make_constant(Value::ValObject(Object::ObjString(name)))
     }
     
     /*
@@ -742,48 +1405,66 @@ impl <'a> Compiler<'a> {
 
         consume identifier token. 
 
-        return the index of the variable in the chunk's constant array.
+        return the slot of the variable if it is a global.
 
     */
-    fn parse_variable(&mut self, error_message: &str) -> u8 {
-        trace!("parse_variable: error_message = {}", error_message.clone());
+    fn parse_variable(&mut self, error_message: &str) -> usize {
+        trace!("parse_variable: error_message = {}", error_message);
         self.consume(TokenType::Identifier, error_message);
 
         self.declare_variable();
 
         // exit if in local scope (depth > 0 means inside a nested block)
         // no need to look up local variables at runtime
-        if self.scope.scope_depth > 0 {  // if scope is not global
+        if self.scope().scope_depth > 0 {  // if scope is not global
+            return 0;
+        }
+        self.global_slot(self.parser.previous)
+    }
+
+    // globals are resolved to their slot in the VM's global table at compile time
+    fn global_slot(&mut self, name: Token) -> usize {
+        let slot = self.globals.resolve(str::from_utf8(name.lexeme).unwrap());
+
+        if slot >= MAX_GLOBALS {
+            self.error("Too many global variables.");
             return 0;
         }
-        self.identifier_constant(self.parser.previous)
+        slot
     }
 
     /*
         Variable name is previous token. 
     */
     
-    fn declare_variable(&mut self) {
+    // returns true if a local was added for the name
+    fn declare_variable(&mut self) -> bool {
         trace!("declare_variable");
+        let name: Token = self.parser.previous;
+
         // if scope is global, we do not need to keep track of variable declarations.
         // as global variables are late bound (looked up by name at runtime)
-        if self.scope.scope_depth == 0 {
-            return;
+        if self.scope().scope_depth == 0 {
+            if self.global_consts.contains_key(name.lexeme) {
+                self.error(&format!("Cannot redefine constant '{}'.", name.to_lexeme()));
+            }
+            return false;
         }
 
-        let name: Token = self.parser.previous;
-
         //locals are appended to end of locals vector. 
         // when defining a new local, start from end and check if any other local has the same name in in the same scope. 
         // re-declaring a variable in the same scope is an error.
-        for local in self.scope.locals.iter().rev().take_while(|local| local.depth == -1 || local.depth >= self.scope.scope_depth) {
-            if name.lexeme == local.name.lexeme {
-                self.error_at_current("Already a variable with this name in this scope");
-                return;
-            }
+        let scope = self.scope();
+        let already_declared = scope.locals.iter().rev()
+            .take_while(|local| local.depth == -1 || local.depth >= scope.scope_depth)
+            .any(|local| name.lexeme == local.name.lexeme);
+
+        if already_declared {
+            self.error_at(name, "Already a variable with this name in this scope");
+            return false;
         }
 
-        self.scope.add_local(name);
+        self.add_local(name)
     }
 
 
@@ -791,19 +1472,25 @@ impl <'a> Compiler<'a> {
         Emit the OpCode for defining a variable.
     */
     
-    fn define_variable(&mut self, global: u8) {
-        trace!("define_variable: global = {}", global.clone());
+    fn define_variable(&mut self, global: usize) {
+        trace!("define_variable: global = {}", global);
         // if scope is local 
-        if self.scope.scope_depth > 0 {
+        if self.scope().scope_depth > 0 {
             self.mark_initialized();
             return;
         }
-        self.emit_bytes(OpCode::OpDefineGlobal.into(), global);
+        self.emit_with_operand(OpCode::OpDefineGlobal, OpCode::OpDefineGlobalLong, global);
     }
 
     
     fn mark_initialized(&mut self) {
-        self.scope.locals.last_mut().unwrap().depth = self.scope.scope_depth;
+        // globals are not tracked in locals
+        if self.scope().scope_depth == 0 {
+            return;
+        }
+
+        let depth = self.scope().scope_depth;
+        self.scope_mut().locals.last_mut().unwrap().depth = depth;
     }
 
     /*
@@ -811,7 +1498,46 @@ impl <'a> Compiler<'a> {
     */
     
     fn emit_byte(&mut self, byte: u8){
-        self.chunk.write(byte, self.parser.previous.line);
+        self.emit_byte_at(byte, self.parser.previous);
+    }
+
+    // emit a byte attributed to a token other than the last one, so errors point at it
+    fn emit_byte_at(&mut self, byte: u8, token: Token<'a>) {
+        self.current_chunk().write(byte, token.line, token.span);
+    }
+
+    /*
+        Emit an instruction popping one value.
+        A pop right after another pop is merged into it, so a run of pops becomes one OpPopN.
+        They are kept apart when a jump lands between them, the jump would skip the merged pops.
+    */
+    fn emit_pop(&mut self) {
+        let end = self.current_chunk().code.len();
+
+        if let Some(offset) = self.current().last_pop {
+            if self.current().jump_target != Some(end) {
+                let code = &mut self.current_chunk().code;
+                if offset + 1 == end && code[offset] == u8::from(OpCode::OpPop) {
+                    code[offset] = OpCode::OpPopN.into();
+                    self.emit_byte(2);
+                    return;
+                }
+                if offset + 2 == end && code[offset] == u8::from(OpCode::OpPopN) && code[offset + 1] < u8::MAX {
+                    code[offset + 1] += 1;
+                    return;
+                }
+            }
+        }
+
+        self.current_mut().last_pop = Some(end);
+        self.emit_byte(OpCode::OpPop.into());
+    }
+
+    // position of the next instruction, remembered as a place a jump lands on
+    fn mark_jump_target(&mut self) -> usize {
+        let target = self.current_chunk().code.len();
+        self.current_mut().jump_target = Some(target);
+        target
     }
 
     
@@ -821,7 +1547,14 @@ impl <'a> Compiler<'a> {
     }
 
     
+    // implicit return at the end of a function body returns void.
+    // initializers always return the instance in slot zero
     fn emit_return(&mut self) {
+        if self.current().function_type == FunctionType::Initializer {
+            self.emit_bytes(OpCode::OpGetLocal.into(), 0);
+        } else {
+            self.emit_byte(OpCode::OpVoid.into());
+        }
         self.emit_byte(OpCode::OpReturn.into());
     }
 
@@ -829,9 +1562,9 @@ impl <'a> Compiler<'a> {
     fn emit_loop(&mut self, loop_start: usize) {
         self.emit_byte(OpCode::OpLoop.into());
 
-        let offset = self.chunk.code.len() - loop_start + 2;
-        if offset as u16 > u16::MAX {
-            self.error_at_current("Loop body too large");
+        let offset = self.current_chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large");
         } 
 
         self.emit_byte(((offset >> 8) & 0xff) as u8);
@@ -841,25 +1574,64 @@ impl <'a> Compiler<'a> {
     }
 
     
-    fn make_constant(&mut self, value: Value) -> u8 {
-        let constant = self.chunk.add_constant(value);
+    /*
+        Add the value to the chunk's constants and return its index.
+        Operands can address up to MAX_CONSTANTS constants, past that it is a compile error.
+    */
+    fn make_constant(&mut self, value: Value) -> usize {
+        let constant = self.current_chunk().add_constant(value);
 
-        if constant > 256 {
-            eprint!("Too many constants in one chunk");
+        if constant >= MAX_CONSTANTS {
+            self.error("Too many constants in one chunk.");
             return 0;
         }
-        constant as u8     
+        constant
+    }
+
+    /*
+        Emit an instruction taking a constant index or a global slot.
+        The long form with a three byte operand is used once the operand no longer fits in a byte.
+    */
+    fn emit_with_operand(&mut self, short: OpCode, long: OpCode, constant: usize) {
+        match u8::try_from(constant) {
+            Ok(constant) => self.emit_bytes(short.into(), constant),
+            Err(_) => {
+                self.emit_byte(long.into());
+                self.emit_byte(((constant >> 16) & 0xff) as u8);
+                self.emit_byte(((constant >> 8) & 0xff) as u8);
+                self.emit_byte((constant & 0xff) as u8);
+            }
+        }
     }
 
     
     fn emit_constant(&mut self, value: Value) {
         let constant = self.make_constant(value);
-        self.emit_bytes(OpCode::OpConstant.into(), constant);
+        self.emit_with_operand(OpCode::OpConstant, OpCode::OpConstantLong, constant);
     }
 
     
-    fn end_compiler(&mut self) {
-        self.emit_return();
+    /*
+        Finish the function currently being compiled and hand back its object.
+
+        A script ending in an expression statement returns the value of that expression,
+        its pop is turned into the return. Not when a jump lands after the pop, that path
+        would have nothing to return.
+    */
+    fn end_compiler(&mut self) -> ObjFunction {
+        let end = self.current_chunk().code.len();
+        let result_pop = self.current().result_pop;
+        match result_pop {
+            Some(offset) if offset + 1 == end && self.current().jump_target != Some(end) => {
+                self.current_chunk().code[offset] = OpCode::OpReturn.into();
+            },
+            _ => self.emit_return(),
+        }
+
+        let state = self.functions.pop().unwrap();
+        let mut function = state.function;
+        function.upvalue_count = state.scope.upvalues.len();
+        function
     }
 
     
@@ -874,14 +1646,14 @@ impl <'a> Compiler<'a> {
     
     fn unary(&mut self) {
         trace!("unary");
-        let operator_type = self.parser.previous.token_type;
+        let operator = self.parser.previous;
 
-        self.expression();
+        self.parse_precedence(Precedence::Unary);
 
-        match operator_type {
-            TokenType::Minus => self.emit_byte(OpCode::OpNegate.into()),
-            TokenType::Bang => self.emit_byte(OpCode::OpNot.into()),
-            _ => return,
+        match operator.token_type {
+            TokenType::Minus => self.emit_byte_at(OpCode::OpNegate.into(), operator),
+            TokenType::Bang => self.emit_byte_at(OpCode::OpNot.into(), operator),
+            _ => (),
         }
     }
 
@@ -892,41 +1664,221 @@ impl <'a> Compiler<'a> {
         let rule: Option<&ParseRule> = self.get_rule(operator_type);
 
         if rule.is_none() {
-            eprintln!("Invalid token type and precedence not found in precedence rules");
+            self.error("Invalid binary operator.");
             return;
         }
 
+        // the instruction is emitted after the right operand, but errors belong to the operator
+        let operator = self.parser.previous;
         self.parse_precedence(Precedence::from(u8::from(rule.unwrap().precedence) + 1));
         
 
         match operator_type {
-            TokenType::BangEqual => self.emit_bytes(OpCode::OpEqual.into(), OpCode::OpNot.into()),
-            TokenType::EqualEqual => self.emit_byte(OpCode::OpEqual.into()),
-            TokenType::Greater => self.emit_byte(OpCode::OpGreater.into()),
-            TokenType::GreaterEqual => self.emit_bytes(OpCode::OpLess.into(), OpCode::OpNot.into()),
-            TokenType::Less => self.emit_byte(OpCode::OpLess.into()),
-            TokenType::LessEqual => self.emit_bytes(OpCode::OpGreater.into(), OpCode::OpNot.into()),
+            TokenType::BangEqual => {
+                self.emit_byte_at(OpCode::OpEqual.into(), operator);
+                self.emit_byte_at(OpCode::OpNot.into(), operator);
+            },
+            TokenType::EqualEqual => self.emit_byte_at(OpCode::OpEqual.into(), operator),
+            TokenType::Greater => self.emit_byte_at(OpCode::OpGreater.into(), operator),
+            TokenType::GreaterEqual => {
+                self.emit_byte_at(OpCode::OpLess.into(), operator);
+                self.emit_byte_at(OpCode::OpNot.into(), operator);
+            },
+            TokenType::Less => self.emit_byte_at(OpCode::OpLess.into(), operator),
+            TokenType::LessEqual => {
+                self.emit_byte_at(OpCode::OpGreater.into(), operator);
+                self.emit_byte_at(OpCode::OpNot.into(), operator);
+            },
             TokenType::Plus => {
-                self.emit_byte(OpCode::OpAdd.into());
+                self.emit_byte_at(OpCode::OpAdd.into(), operator);
             },
             TokenType::Minus => {
-                self.emit_byte(OpCode::OpSubtract.into());
+                self.emit_byte_at(OpCode::OpSubtract.into(), operator);
             },
             TokenType::Star => {
-                self.emit_byte(OpCode::OpMultiply.into());
+                self.emit_byte_at(OpCode::OpMultiply.into(), operator);
             },
             TokenType::Slash => {
-                self.emit_byte(OpCode::OpDivide.into());
+                self.emit_byte_at(OpCode::OpDivide.into(), operator);
             },
 
-            _ => {
-                return;
+            _ => (),
+        }
+    }
+
+    // 'start..end' and 'start..=end', the bounds bind tighter than comparisons but looser than arithmetic
+    fn range(&mut self) {
+        trace!("range");
+        let inclusive = self.parser.previous.token_type == TokenType::DotDotEqual;
+        self.parse_precedence(Precedence::Term);
+        self.emit_bytes(OpCode::OpRange.into(), inclusive as u8);
+    }
+
+    
+    /*
+        Infix rule for '(' - the callee has already been compiled and is on the stack.
+    */
+    fn call(&mut self) {
+        trace!("call");
+        let arg_count = self.argument_list();
+        self.emit_bytes(OpCode::OpCall.into(), arg_count);
+    }
+
+    /*
+        Infix rule for '.' - the instance has already been compiled and is on the stack.
+
+        Handles property access, property assignment and, when a call follows directly,
+        invoking a method in a single instruction.
+    */
+    fn dot(&mut self, can_assign: bool) {
+        trace!("dot");
+        self.consume(TokenType::Identifier, "Expecting property name after '.'.");
+        let name = self.identifier_constant(self.parser.previous);
+
+        if can_assign && self.is_match(TokenType::Equal) {
+            self.expression();
+            self.emit_with_operand(OpCode::OpSetProperty, OpCode::OpSetPropertyLong, name);
+        } else if self.is_match(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.emit_with_operand(OpCode::OpInvoke, OpCode::OpInvokeLong, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.emit_with_operand(OpCode::OpGetProperty, OpCode::OpGetPropertyLong, name);
+        }
+    }
+
+    // 'this' is the local in slot zero of every method
+    fn this(&mut self) {
+        trace!("this");
+        if self.classes.is_empty() {
+            self.error("Cannot use 'this' outside of a class.");
+            return;
+        }
+
+        self.variable(false);
+    }
+
+    /*
+        'super.method' looks the method up on the superclass, starting from the class the
+        method was declared in rather than the class of the instance.
+        Needs both 'this' and 'super' on the stack.
+    */
+    fn super_(&mut self) {
+        trace!("super");
+        match self.classes.last() {
+            None => self.error("Cannot use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error_with_hint(self.parser.previous, "Cannot use 'super' in a class with no superclass.", Some("inherit with 'class Name < Superclass'."));
+            },
+            Some(_) => (),
+        }
+
+        self.consume(TokenType::Dot, "Expecting '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expecting superclass method name.");
+        let name = self.identifier_constant(self.parser.previous);
+
+        self.named_variable(synthetic_token("this"), false);
+        if self.is_match(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable(synthetic_token("super"), false);
+            self.emit_with_operand(OpCode::OpSuperInvoke, OpCode::OpSuperInvokeLong, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable(synthetic_token("super"), false);
+            self.emit_with_operand(OpCode::OpGetSuper, OpCode::OpGetSuperLong, name);
+        }
+    }
+
+    /*
+        List literal. Elements are left on the stack and collected into a list by the VM.
+        A trailing comma after the last element is allowed.
+    */
+    fn list(&mut self) {
+        trace!("list");
+        let mut count: usize = 0;
+
+        while !self.check(TokenType::RightBracket) && !self.check(TokenType::Eof) {
+            self.expression();
+            if count == 255 {
+                self.error("Cannot have more than 255 elements in a list literal.");
             }
+            count += 1;
 
+            if !self.is_match(TokenType::Comma) {
+                break;
+            }
         }
+
+        self.consume(TokenType::RightBracket, "Expecting ']' after list elements.");
+        self.emit_bytes(OpCode::OpBuildList.into(), count as u8);
+    }
+
+    /*
+        Map literal, e.g. {"a": 1, "b": 2}. Keys and values are left on the stack in
+        pairs and collected into a map by the VM.
+
+        A '{' at the start of a statement is always a block, so map literals only
+        appear in expression position.
+    */
+    fn map(&mut self) {
+        trace!("map");
+        let mut count: usize = 0;
+
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.expression();
+            self.consume(TokenType::Colon, "Expecting ':' after map key.");
+            self.expression();
+
+            if count == 255 {
+                self.error("Cannot have more than 255 entries in a map literal.");
+            }
+            count += 1;
+
+            if !self.is_match(TokenType::Comma) {
+                break;
+            }
+        }
+
+        self.consume(TokenType::RightBrace, "Expecting '}' after map entries.");
+        self.emit_bytes(OpCode::OpBuildMap.into(), count as u8);
+    }
+
+    // infix rule for '[' - the indexed value is already on the stack
+    fn index(&mut self, can_assign: bool) {
+        trace!("index");
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expecting ']' after index.");
+
+        if can_assign && self.is_match(TokenType::Equal) {
+            self.expression();
+            self.emit_byte(OpCode::OpSetIndex.into());
+        } else {
+            self.emit_byte(OpCode::OpGetIndex.into());
+        }
+    }
+
+    // compile the arguments of a call and return how many there were
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == 255 {
+                    self.error("Cannot have more than 255 arguments.");
+                }
+                arg_count += 1;
+
+                if !self.is_match(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RightParen, "Expecting ')' after arguments.");
+        arg_count as u8
     }
 
-    
     fn grouping(&mut self) {
         trace!("grouping");
         self.expression();
@@ -941,34 +1893,111 @@ impl <'a> Compiler<'a> {
             TokenType::False => self.emit_byte(OpCode::OpFalse.into()),
             TokenType::Void => self.emit_byte(OpCode::OpVoid.into()),
             TokenType::True => self.emit_byte(OpCode::OpTrue.into()),
-            _ => {
-                return;
-            }
+            _ => (),
         }
     }
 
     
+    /*
+        Emit the string literal (or the literal part of an interpolated string) in the previous token.
+        Raw strings are taken as written, multi-line strings lose their common indentation
+        before escape sequences are decoded.
+    */
     fn string(&mut self) {
         trace!("string");
-        self.emit_constant(
-            Value::ValObject(
-                Object::ObjString(
-                    ObjString::from_str(
-                        str::from_utf8(
-                            self.parser.previous.lexeme)
-                            .unwrap_or("")
-                            .trim_start_matches('"').trim_end_matches('"')
-                        )
-                    )
-                )
-            );
-            
+        let token = self.parser.previous;
+        let lexeme = token.to_lexeme();
+
+        let text = match token.token_type {
+            TokenType::RawString => Some(lexeme[2..lexeme.len() - 1].to_string()),
+            TokenType::MultilineString => self.unescape(&dedent(&lexeme[3..lexeme.len() - 3])),
+            _ => self.unescape(string_segment(token)),
+        };
+
+        if let Some(text) = text {
+            let string = self.strings.intern(&text);
+            self.emit_constant(Value::ValObject(Object::ObjString(string)));
+        }
+    }
+
+    /*
+        Decode the escape sequences in a string literal.
+
+        Supported: \n \t \r \0 \\ \" \$ and \u{...} with 1 to 6 hex digits.
+        Reports a compile error at the string token and returns None for anything else.
+    */
+    fn unescape(&mut self, text: &str) -> Option<String> {
+        let mut result = String::with_capacity(text.len());
+        let mut chars = text.chars();
+
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                result.push(c);
+                continue;
+            }
+
+            let decoded = match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some('\\') => '\\',
+                Some('"') => '"',
+                Some('$') => '$',
+                Some('u') => match decode_unicode_escape(&mut chars) {
+                    Some(decoded) => decoded,
+                    None => {
+                        self.error("Invalid unicode escape sequence, expecting \\u{...} with 1 to 6 hex digits.");
+                        return None;
+                    }
+                },
+                Some(other) => {
+                    self.error(&format!("Invalid escape sequence '\\{}'.", other));
+                    return None;
+                },
+                None => {
+                    self.error("Unfinished escape sequence at end of string.");
+                    return None;
+                },
+            };
+            result.push(decoded);
+        }
+
+        Some(result)
+    }
+
+    /*
+        Prefix rule for a string with ${...} in it.
+
+        "a ${x} b" compiles to "a " + str(x) + " b". The first part is always emitted,
+        even when empty, so the result is a string whatever the expressions evaluate to.
+    */
+    fn interpolation(&mut self) {
+        trace!("interpolation");
+        self.string();
+
+        loop {
+            self.expression();
+            self.emit_byte(OpCode::OpToString.into());
+            self.emit_byte(OpCode::OpAdd.into());
+
+            let more = self.is_match(TokenType::Interpolation);
+            if !more {
+                self.consume(TokenType::String, "Expecting end of string interpolation.");
+                if self.parser.previous.token_type != TokenType::String {
+                    return;
+                }
+            }
+
+            if !string_segment(self.parser.previous).is_empty() {
+                self.string();
+                self.emit_byte(OpCode::OpAdd.into());
+            }
 
-        // self.emit_constant(Value::ValObject(
-        //     Object::from_str(
-                // str::from_utf8(
-                //     self.parser.previous.lexeme).unwrap_or("")
-                //     .trim_start_matches('"').trim_end_matches('"'))));
+            if !more {
+                return;
+            }
+        }
     }
 
     /*
@@ -980,87 +2009,170 @@ impl <'a> Compiler<'a> {
     */
     
     fn named_variable(&mut self, name: Token, can_assign: bool) {
-        trace!("named_variable: name = {:?}, can_assign = {:?}", name.clone(), can_assign.clone());
+        trace!("named_variable: name = {:?}, can_assign = {:?}", name, can_assign);
+
+        let const_value = self.lookup_const(name);
+        if let Some(inline_value) = &const_value {
+            if can_assign && self.check(TokenType::Equal) {
+                self.error_with_hint(name, &format!("Cannot assign to constant '{}'.", name.to_lexeme()), Some("declare it with 'create' to be able to assign to it."));
+            } else if let Some(value) = inline_value {
+                self.emit_literal(value.clone());
+                return;
+            }
+        }
 
         //some rust magic
-        let (arg, set_op, get_op) = if let Some(index) = self.resolve_local(name) {
-            (index as u8, OpCode::OpSetLocal, OpCode::OpGetLocal)
+        let current = self.functions.len() - 1;
+        let (arg, set_op, get_op) = if let Some(index) = self.resolve_local(current, name) {
+            (index, OpCode::OpSetLocal, OpCode::OpGetLocal)
+        } else if let Some(index) = self.resolve_upvalue(current, name) {
+            (index, OpCode::OpSetUpvalue, OpCode::OpGetUpvalue)
         } else {
-            (self.identifier_constant(name), OpCode::OpSetGlobal, OpCode::OpGetGlobal)
+            let global = self.global_slot(name);
+            let is_assignment = can_assign && self.is_match(TokenType::Equal);
+
+            // globals are addressed by slot which may need the long instructions
+            if is_assignment {
+                self.expression();
+                self.emit_with_operand(OpCode::OpSetGlobal, OpCode::OpSetGlobalLong, global);
+            } else {
+                self.emit_with_operand(OpCode::OpGetGlobal, OpCode::OpGetGlobalLong, global);
+            }
+            return;
         };
 
-        //println!("{} {:?} {:?}", arg, set_op, get_op);
-        
         if can_assign && self.is_match(TokenType::Equal) {
             // we found an assignment, compile the expression on the right hand side
             self.expression();
-            self.emit_bytes(set_op.into(), arg);
+            self.emit_bytes(set_op.into(), arg as u8);
         } else {
-            self.emit_bytes(get_op.into(), arg)
+            self.emit_bytes(get_op.into(), arg as u8)
         }
 
     }
 
     /*
-        Just checks if given token is a local variable in current scope
+        Just checks if given token is a local variable in the scope of the function at function_index
         Returns the index of the local variable in the scope's locals vector if found.
     */
-    fn resolve_local(&mut self, name: Token) -> Option<usize> {
-        for (index, local)  in self.scope.locals.iter().enumerate().rev() {
-            if name.lexeme == local.name.lexeme {
-                if local.depth == -1 {
-                    self.error_at_current("Cannot read local variable in its own initializer.");
+    fn resolve_local(&mut self, function_index: usize, name: Token) -> Option<usize> {
+        let found = self.functions[function_index].scope.locals.iter().enumerate().rev()
+            .find(|(_, local)| name.lexeme == local.name.lexeme)
+            .map(|(index, local)| (index, local.depth));
+
+        match found {
+            Some((index, depth)) => {
+                if depth == -1 {
+                    self.error_at(name, "Cannot read local variable in its own initializer.");
                 }
-                return Some(index)
-            }
+                Some(index)
+            },
+            None => None,
         }
+    }
+
+    /*
+        Look for the variable in the enclosing functions, innermost first.
+
+        If it is a local of the directly enclosing function, capture that local.
+        Otherwise the enclosing function has to capture it as an upvalue itself, which
+        threads the variable through every function in between.
+        Returns the index of the upvalue in the function at function_index.
+    */
+    fn resolve_upvalue(&mut self, function_index: usize, name: Token) -> Option<usize> {
+        if function_index == 0 {
+            return None;
+        }
+
+        let enclosing = function_index - 1;
+
+        if let Some(local) = self.resolve_local(enclosing, name) {
+            self.functions[enclosing].scope.locals[local].is_captured = true;
+            return Some(self.add_upvalue(function_index, local as u8, true));
+        }
+
+        if let Some(upvalue) = self.resolve_upvalue(enclosing, name) {
+            return Some(self.add_upvalue(function_index, upvalue as u8, false));
+        }
+
         None
     }
+
+    // returns the index of the upvalue, reusing an existing one if the variable is already captured
+    fn add_upvalue(&mut self, function_index: usize, index: u8, is_local: bool) -> usize {
+        let upvalue = Upvalue { index, is_local };
+        let upvalues = &self.functions[function_index].scope.upvalues;
+
+        if let Some(existing) = upvalues.iter().position(|captured| *captured == upvalue) {
+            return existing;
+        }
+
+        if upvalues.len() == 256 {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+
+        self.functions[function_index].scope.upvalues.push(upvalue);
+        self.functions[function_index].scope.upvalues.len() - 1
+    }
     
     
     fn variable(&mut self, can_assign: bool) {
-        trace!("variable: can_assign = {:?}", can_assign.clone());
+        trace!("variable: can_assign = {:?}", can_assign);
         self.named_variable(self.parser.previous, can_assign);
     }
 
     
     fn error_at_current(&mut self, message: &str) {
-        self.error_at(self.parser.previous, message);
+        self.error_at(self.parser.current, message);
+    }
 
+    fn error(&mut self, message: &str) {
+        self.error_at(self.parser.previous, message);
     }
 
     
     fn error_at(&mut self, token: Token, message: &str) {
+        self.error_with_hint(token, message, None);
+    }
+
+    // after an error the rest of the statement is skipped without reporting, it would likely follow from the first
+    fn error_with_hint(&mut self, token: Token, message: &str, hint: Option<&str>) {
         if self.parser.panic_mode {
             return
         }
 
         self.parser.panic_mode = true;
+        self.report(token, Severity::Error, message, hint);
+    }
 
-        eprint!("[line {}] Error", token.line);
-
-        match token.token_type {
-            TokenType::Eof => {
-                eprint!(" at the end");
-            },
-            TokenType::Error => {},
-            _ => {
-                eprint!(" at  {}", str::from_utf8(token.lexeme).unwrap());
-            }
-        }
-
-        eprint!(" : {}", message);
-
-        self.parser.had_error = true;
+    fn warning_at(&mut self, token: Token, message: &str) {
+        self.report(token, Severity::Warning, message, None);
+    }
 
+    fn report(&mut self, token: Token, severity: Severity, message: &str, hint: Option<&str>) {
+        let location = match token.token_type {
+            TokenType::Eof => Some(String::from("end")),
+            TokenType::Error => None,
+            _ => Some(format!("'{}'", String::from_utf8_lossy(token.lexeme))),
+        };
 
+        self.parser.diagnostics.push(Diagnostic {
+            severity,
+            message: message.to_string(),
+            span: token.span,
+            location,
+            hint: hint.map(String::from),
+        });
     }
 
     /*
-        Compile the source code into bytecode and store it in the chunk.
+        Compile the source code into bytecode.
+
+        Returns the top level script as a function along with every diagnostic found in the source.
+        There is no function if any of them is an error.
     */
-  //  
-    pub fn compile(&mut self) -> bool {    
+    pub fn compile(&mut self) -> (Option<ObjFunction>, Vec<Diagnostic>) {
         trace!("compilation started");  
         self.advance();
 
@@ -1068,10 +2180,13 @@ impl <'a> Compiler<'a> {
             self.declaration();
         }
 
-        self.end_compiler();
-
-        !self.parser.had_error
+        let function = self.end_compiler();
+        let diagnostics = std::mem::take(&mut self.parser.diagnostics);
 
+        match diagnostics.iter().any(Diagnostic::is_error) {
+            true => (None, diagnostics),
+            false => (Some(function), diagnostics),
+        }
     }
 
 }
@@ -1,77 +1,160 @@
-use crate::{chunk::{Chunk, OpCode}, debug};
-use log::debug as log_debug;
+/*
+    Disassembler for compiled bytecode.
+
+    Listings are rendered into any fmt::Write, one instruction per line:
+    the offset, the source line or | when it is the same as the previous instruction's,
+    the instruction and its operands. Constants are shown with their value, global slots with the
+    name of the variable and jumps with the offset they land on.
+*/
+use crate::chunk::{Chunk, OpCode};
+use crate::globals::GlobalNames;
+use crate::object::{Object, ObjFunction};
+use crate::value::Value;
+use std::fmt;
+use std::io;
 
-#[allow(dead_code)]
-// given a chunk, print all instructions in the chunk
-pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
-    log_debug!("== {} ==", name);
-    
-    // offset is used to keep track of the current position in the bytecode instructions
-    let mut offset: u8 = 0;  // TODO should this be a bigger type?
+// list the function's chunk followed by the chunks of every function declared in it
+pub fn disassemble_function(out: &mut dyn fmt::Write, function: &ObjFunction, globals: &GlobalNames) -> fmt::Result {
+    let name = match function.name {
+        Some(_) => function.display_name(),
+        None => "<script>",
+    };
+    disassemble_chunk(out, &function.chunk, name, globals)?;
+
+    for constant in &function.chunk.constants {
+        if let Value::ValObject(Object::ObjFunction(nested)) = constant {
+            writeln!(out)?;
+            disassemble_function(out, nested, globals)?;
+        }
+    }
+    Ok(())
+}
+
+// write the listing of the function to a file, stdout or any other writer
+pub fn write_function(out: &mut dyn io::Write, function: &ObjFunction, globals: &GlobalNames) -> io::Result<()> {
+    let mut listing = String::new();
+    disassemble_function(&mut listing, function, globals).map_err(io::Error::other)?;
+    out.write_all(listing.as_bytes())
+}
+
+// given a chunk, list all instructions in the chunk
+pub fn disassemble_chunk(out: &mut dyn fmt::Write, chunk: &Chunk, name: &str, globals: &GlobalNames) -> fmt::Result {
+    writeln!(out, "== {} ==", name)?;
 
     // instructions can have different lengths so get next offset from disassemble_instruction
-    while offset < chunk.code.len() as u8{
-        offset = disassemble_instruction(chunk, offset);
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        offset = disassemble_instruction(out, chunk, offset, globals)?;
     }
+    Ok(())
 }
 
-// disassemble_instruction takes a chunk and an offset, and prints the instruction at that offset
-pub fn disassemble_instruction(chunk: &Chunk, offset: u8) -> u8 {
-    let mut debug_string = "".to_string();
-    debug_string.push_str(&format!("{:03} ", offset));
+/*
+    disassemble_instruction takes a chunk and an offset, writes the instruction at that offset and returns the offset of the next one.
+    globals are the names of the global slots the chunk was compiled or loaded against.
+*/
+pub fn disassemble_instruction(out: &mut dyn fmt::Write, chunk: &Chunk, offset: usize, globals: &GlobalNames) -> Result<usize, fmt::Error> {
+    write!(out, "{:04} ", offset)?;
 
-    // if offset is not the first instruction, print the line number
-    // TODO: equality check seems weird
-    if offset > 0 && chunk.get_line(offset as usize) == chunk.get_line(offset as usize) {
-        debug_string.push_str("| ");
+    // operands are written with the line of their instruction, so the byte before belongs to the previous instruction
+    let line = chunk.get_line(offset);
+    if offset > 0 && line == chunk.get_line(offset - 1) {
+        write!(out, "   | ")?;
     } else {
-        debug_string.push_str(&chunk.get_line(offset as usize).to_string());
-        debug_string.push_str(" ");
+        write!(out, "{:4} ", line)?;
     }
-    
-    let instruction = chunk.code[offset as usize];
-    let code: OpCode = instruction.into();
+
+    let code = match OpCode::from_byte(chunk.code[offset]) {
+        Some(code) => code,
+        None => {
+            writeln!(out, "Unknown opcode {}", chunk.code[offset])?;
+            return Ok(offset + 1);
+        }
+    };
+
     match code {
-        OpCode::OpReturn => return simple_instruction("OP_RETURN", offset, debug_string),
-        OpCode::OpConstant => return constant_instruction("OP_CONSTANT", chunk, offset, debug_string),
-        OpCode::OpNegate => return simple_instruction("OP_NEGATE", offset, debug_string),
-        OpCode::OpAdd => return simple_instruction("OP_ADD", offset, debug_string),
-        OpCode::OpSubtract => return simple_instruction("OP_SUBTRACT", offset, debug_string),
-        OpCode::OpMultiply => return simple_instruction("OP_MULTIPLY", offset, debug_string),
-        OpCode::OpDivide => return simple_instruction("OP_DIVIDE", offset, debug_string),
-        OpCode::OpNot => return simple_instruction("OP_NOT", offset, debug_string),
-        OpCode::OpVoid => return simple_instruction("OP_VOID", offset, debug_string),
-        OpCode::OpTrue => return simple_instruction("OP_TRUE", offset, debug_string),
-        OpCode::OpFalse => return simple_instruction("OP_FALSE", offset, debug_string),
-        OpCode::OpEqual => return simple_instruction("OP_EQUAL", offset, debug_string),
-        OpCode::OpGreater => return simple_instruction("OP_GREATER", offset, debug_string),
-        OpCode::OpLess => return simple_instruction("OP_LESS", offset, debug_string),
-        OpCode::OpEmit => return simple_instruction("OP_EMIT", offset, debug_string),
-        OpCode::OpPop => return simple_instruction("OP_POP", offset, debug_string),
-        OpCode::OpDefineGlobal => return constant_instruction("OP_DEFINE_GLOBAL", chunk, offset, debug_string),
-        OpCode::OpGetGlobal => return constant_instruction("OP_GET_GLOBAL", chunk, offset, debug_string),
-        OpCode::OpSetGlobal => return constant_instruction("OP_SET_GLOBAL", chunk, offset, debug_string),
-        OpCode::OpGetLocal => return byte_instruction("OP_GET_LOCAL", chunk, offset, debug_string),
-        OpCode::OpSetLocal => return byte_instruction("OP_SET_LOCAL", chunk, offset, debug_string),
-        OpCode::OpJump => return jump_instruction("OP_JUMP", chunk, 1, offset, debug_string),
-        OpCode::OpJumpIfFalse => return jump_instruction("OP_JUMP_IF_FALSE", chunk, 1, offset, debug_string),
-        OpCode::OpLoop => return jump_instruction("OP_LOOP", chunk, -1, offset, debug_string)
+        OpCode::OpReturn => simple_instruction(out, "OP_RETURN", offset),
+        OpCode::OpConstant => constant_instruction(out, "OP_CONSTANT", chunk, offset),
+        OpCode::OpNegate => simple_instruction(out, "OP_NEGATE", offset),
+        OpCode::OpAdd => simple_instruction(out, "OP_ADD", offset),
+        OpCode::OpSubtract => simple_instruction(out, "OP_SUBTRACT", offset),
+        OpCode::OpMultiply => simple_instruction(out, "OP_MULTIPLY", offset),
+        OpCode::OpDivide => simple_instruction(out, "OP_DIVIDE", offset),
+        OpCode::OpNot => simple_instruction(out, "OP_NOT", offset),
+        OpCode::OpVoid => simple_instruction(out, "OP_VOID", offset),
+        OpCode::OpTrue => simple_instruction(out, "OP_TRUE", offset),
+        OpCode::OpFalse => simple_instruction(out, "OP_FALSE", offset),
+        OpCode::OpEqual => simple_instruction(out, "OP_EQUAL", offset),
+        OpCode::OpGreater => simple_instruction(out, "OP_GREATER", offset),
+        OpCode::OpLess => simple_instruction(out, "OP_LESS", offset),
+        OpCode::OpEmit => simple_instruction(out, "OP_EMIT", offset),
+        OpCode::OpPop => simple_instruction(out, "OP_POP", offset),
+        OpCode::OpPopN => byte_instruction(out, "OP_POP_N", chunk, offset),
+        OpCode::OpRange => byte_instruction(out, "OP_RANGE", chunk, offset),
+        OpCode::OpForIter => for_iter_instruction(out, "OP_FOR_ITER", chunk, offset),
+        OpCode::OpDefineGlobal => global_instruction(out, "OP_DEFINE_GLOBAL", chunk, offset, globals),
+        OpCode::OpGetGlobal => global_instruction(out, "OP_GET_GLOBAL", chunk, offset, globals),
+        OpCode::OpSetGlobal => global_instruction(out, "OP_SET_GLOBAL", chunk, offset, globals),
+        OpCode::OpGetLocal => byte_instruction(out, "OP_GET_LOCAL", chunk, offset),
+        OpCode::OpSetLocal => byte_instruction(out, "OP_SET_LOCAL", chunk, offset),
+        OpCode::OpJump => jump_instruction(out, "OP_JUMP", chunk, 1, offset),
+        OpCode::OpJumpIfFalse => jump_instruction(out, "OP_JUMP_IF_FALSE", chunk, 1, offset),
+        OpCode::OpLoop => jump_instruction(out, "OP_LOOP", chunk, -1, offset),
+        OpCode::OpCall => byte_instruction(out, "OP_CALL", chunk, offset),
+        OpCode::OpClosure => closure_instruction(out, "OP_CLOSURE", chunk, offset),
+        OpCode::OpGetUpvalue => byte_instruction(out, "OP_GET_UPVALUE", chunk, offset),
+        OpCode::OpSetUpvalue => byte_instruction(out, "OP_SET_UPVALUE", chunk, offset),
+        OpCode::OpCloseUpvalue => simple_instruction(out, "OP_CLOSE_UPVALUE", offset),
+        OpCode::OpClass => constant_instruction(out, "OP_CLASS", chunk, offset),
+        OpCode::OpGetProperty => constant_instruction(out, "OP_GET_PROPERTY", chunk, offset),
+        OpCode::OpSetProperty => constant_instruction(out, "OP_SET_PROPERTY", chunk, offset),
+        OpCode::OpMethod => constant_instruction(out, "OP_METHOD", chunk, offset),
+        OpCode::OpInvoke => invoke_instruction(out, "OP_INVOKE", chunk, offset),
+        OpCode::OpInherit => simple_instruction(out, "OP_INHERIT", offset),
+        OpCode::OpGetSuper => constant_instruction(out, "OP_GET_SUPER", chunk, offset),
+        OpCode::OpSuperInvoke => invoke_instruction(out, "OP_SUPER_INVOKE", chunk, offset),
+        OpCode::OpBuildList => byte_instruction(out, "OP_BUILD_LIST", chunk, offset),
+        OpCode::OpGetIndex => simple_instruction(out, "OP_GET_INDEX", offset),
+        OpCode::OpSetIndex => simple_instruction(out, "OP_SET_INDEX", offset),
+        OpCode::OpBuildMap => byte_instruction(out, "OP_BUILD_MAP", chunk, offset),
+        OpCode::OpToString => simple_instruction(out, "OP_TO_STRING", offset),
+        OpCode::OpDefineConstGlobal => global_instruction(out, "OP_DEFINE_CONST_GLOBAL", chunk, offset, globals),
+        OpCode::OpConstantLong => constant_instruction(out, "OP_CONSTANT_LONG", chunk, offset),
+        OpCode::OpDefineGlobalLong => global_instruction(out, "OP_DEFINE_GLOBAL_LONG", chunk, offset, globals),
+        OpCode::OpGetGlobalLong => global_instruction(out, "OP_GET_GLOBAL_LONG", chunk, offset, globals),
+        OpCode::OpSetGlobalLong => global_instruction(out, "OP_SET_GLOBAL_LONG", chunk, offset, globals),
+        OpCode::OpDefineConstGlobalLong => global_instruction(out, "OP_DEFINE_CONST_GLOBAL_LONG", chunk, offset, globals),
+        OpCode::OpClosureLong => closure_instruction(out, "OP_CLOSURE_LONG", chunk, offset),
+        OpCode::OpClassLong => constant_instruction(out, "OP_CLASS_LONG", chunk, offset),
+        OpCode::OpGetPropertyLong => constant_instruction(out, "OP_GET_PROPERTY_LONG", chunk, offset),
+        OpCode::OpSetPropertyLong => constant_instruction(out, "OP_SET_PROPERTY_LONG", chunk, offset),
+        OpCode::OpMethodLong => constant_instruction(out, "OP_METHOD_LONG", chunk, offset),
+        OpCode::OpInvokeLong => invoke_instruction(out, "OP_INVOKE_LONG", chunk, offset),
+        OpCode::OpGetSuperLong => constant_instruction(out, "OP_GET_SUPER_LONG", chunk, offset),
+        OpCode::OpSuperInvokeLong => invoke_instruction(out, "OP_SUPER_INVOKE_LONG", chunk, offset),
     }
 }
 
-fn jump_instruction(name: &str, chunk: &Chunk, sign: i16, offset: u8, mut debug_string: String) -> u8{
-    let jump = (usize::from(chunk.code[offset as usize + 1]) << 8) | usize::from(chunk.code[offset as usize + 2]);
+fn jump_instruction(out: &mut dyn fmt::Write, name: &str, chunk: &Chunk, sign: i16, offset: usize) -> Result<usize, fmt::Error> {
+    let jump = (usize::from(chunk.code[offset + 1]) << 8) | usize::from(chunk.code[offset + 2]);
 
     let jump_to = if sign > 0 {
-        offset as usize + 3 + jump
+        offset + 3 + jump
     } else {
-        offset as usize + 3 - jump
+        (offset + 3).wrapping_sub(jump)
     };
 
-    debug_string.push_str(&format!("{} {} -> {}", name, jump, jump_to));
-    log_debug!("{}", debug_string);
+    writeln!(out, "{:<24} {:4} -> {}", name, jump, jump_to)?;
+    Ok(offset + 3)
+}
+
+// slot of the iterated value followed by the jump taken once it is exhausted
+fn for_iter_instruction(out: &mut dyn fmt::Write, name: &str, chunk: &Chunk, offset: usize) -> Result<usize, fmt::Error> {
+    let slot = chunk.code[offset + 1];
+    let jump = (usize::from(chunk.code[offset + 2]) << 8) | usize::from(chunk.code[offset + 3]);
 
-    offset + 3
+    writeln!(out, "{:<24} {:4} -> {}", name, slot, offset + 4 + jump)?;
+    Ok(offset + 4)
 }
 
 /*
@@ -79,31 +162,95 @@ fn jump_instruction(name: &str, chunk: &Chunk, sign: i16, offset: u8, mut debug_
     chunk: the chunk containing the instruction
     offset: the offset of the instruction in the chunk
 */
-fn constant_instruction(name: &str, chunk: &Chunk, offset: u8, mut debug_string: String) -> u8 {
-    let constant_index = chunk.code[offset as usize + 1]; // constant is stored after the opcode
-    //log_debug!("constant instruction {} {}", name, constant_index); // this is constant index
-    let constant_value = chunk.constants[constant_index as usize].read_value_str();
-
-    debug_string.push_str(&format!("{} {} {}", name, constant_index, constant_value));
-    log_debug!("{}", debug_string);
-    
-    offset + 2
+fn constant_instruction(out: &mut dyn fmt::Write, name: &str, chunk: &Chunk, offset: usize) -> Result<usize, fmt::Error> {
+    let (constant_index, next) = constant_operand(chunk, offset); // constant is stored after the opcode
+
+    writeln!(out, "{:<24} {:4} {}", name, constant_index, constant_display(chunk, constant_index))?;
+    Ok(next)
+}
+
+// the operand of global instructions is the slot of the variable, not a constant
+fn global_instruction(out: &mut dyn fmt::Write, name: &str, chunk: &Chunk, offset: usize, globals: &GlobalNames) -> Result<usize, fmt::Error> {
+    let (slot, next) = constant_operand(chunk, offset);
+    let variable = match slot < globals.len() {
+        true => globals.name(slot),
+        false => "<unknown global>",
+    };
+
+    writeln!(out, "{:<24} {:4} {}", name, slot, variable)?;
+    Ok(next)
+}
+
+// method name constant followed by the argument count
+fn invoke_instruction(out: &mut dyn fmt::Write, name: &str, chunk: &Chunk, offset: usize) -> Result<usize, fmt::Error> {
+    let (constant_index, next) = constant_operand(chunk, offset);
+    let arg_count = chunk.code[next];
+
+    writeln!(out, "{:<24} {:4} {} ({} args)", name, constant_index, constant_display(chunk, constant_index), arg_count)?;
+    Ok(next + 1)
+}
+
+// value of the constant, quoted, or a marker if the index is past the constants of the chunk
+fn constant_display(chunk: &Chunk, index: usize) -> String {
+    match chunk.constants.get(index) {
+        Some(constant) => constant.read_value_str(),
+        None => String::from("<missing constant>"),
+    }
+}
+
+/*
+    Read the constant index following the opcode at offset.
+    Long instructions store it in three bytes, high byte first, the others in one.
+    Returns the index and the offset right after it.
+*/
+fn constant_operand(chunk: &Chunk, offset: usize) -> (usize, usize) {
+    let code: OpCode = chunk.code[offset].into();
+    match code.is_long() {
+        true => {
+            let index = (usize::from(chunk.code[offset + 1]) << 16)
+                | (usize::from(chunk.code[offset + 2]) << 8)
+                | usize::from(chunk.code[offset + 3]);
+            (index, offset + 4)
+        },
+        false => (usize::from(chunk.code[offset + 1]), offset + 2),
+    }
 }
 
 /*
-    Print name of the instruction and increment offset by 1
+    OP_CLOSURE is variable length. After the function constant there is one pair of bytes
+    for every upvalue the function captures, each listed on its own line.
 */
-fn simple_instruction(name: &str, offset: u8, mut debug_string: String) -> u8 {
-    debug_string.push_str(name);
-    log_debug!("{}", debug_string);
+fn closure_instruction(out: &mut dyn fmt::Write, name: &str, chunk: &Chunk, offset: usize) -> Result<usize, fmt::Error> {
+    let (constant_index, mut next) = constant_operand(chunk, offset);
+
+    writeln!(out, "{:<24} {:4} {}", name, constant_index, constant_display(chunk, constant_index))?;
+
+    let upvalue_count = match chunk.constants.get(constant_index) {
+        Some(Value::ValObject(Object::ObjFunction(function))) => function.upvalue_count,
+        _ => 0,
+    };
+
+    for _ in 0..upvalue_count {
+        let is_local = chunk.code[next];
+        let index = chunk.code[next + 1];
+        let kind = if is_local == 1 { "local" } else { "upvalue" };
+        writeln!(out, "{:04}    | {:<24} {:4} {}", next, "", index, kind)?;
+        next += 2;
+    }
 
-    offset + 1
+    Ok(next)
 }
 
-fn byte_instruction(name: &str, chunk: &Chunk, offset: u8, mut debug_string: String) -> u8 {
-    let slot = chunk.code[offset as usize + 1];
-    debug_string.push_str(&format!("{} {}", name, slot));
-    log_debug!("{}", debug_string);
+/*
+    Write name of the instruction and increment offset by 1
+*/
+fn simple_instruction(out: &mut dyn fmt::Write, name: &str, offset: usize) -> Result<usize, fmt::Error> {
+    writeln!(out, "{}", name)?;
+    Ok(offset + 1)
+}
 
-    offset + 2
-}
\ No newline at end of file
+fn byte_instruction(out: &mut dyn fmt::Write, name: &str, chunk: &Chunk, offset: usize) -> Result<usize, fmt::Error> {
+    let slot = chunk.code[offset + 1];
+    writeln!(out, "{:<24} {:4}", name, slot)?;
+    Ok(offset + 2)
+}
@@ -0,0 +1,101 @@
+/*
+    Problems the compiler reports about the source.
+
+    Compiling goes on after an error to report as many as it can, so a run hands back all of
+    them at once. Warnings do not stop the script from compiling.
+*/
+use crate::span::{self, Span};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+            Severity::Warning => write!(f, "Warning"),
+        }
+    }
+}
+
+/*
+    location is the lexeme of the token the diagnostic is about, "end" at the end of the source,
+    or None when the scanner could not make a token. span is where that token is.
+    hint suggests how to fix it, when there is an obvious way.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+    pub location: Option<String>,
+    pub hint: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    // file:line:col, the message, the source line with the token underlined if the source is given, and the hint
+    pub fn render(&self, file: &str, source: Option<&str>) -> String {
+        let mut rendered = format!("{}:{}:{}: {}", file, self.span.line, self.span.column, self.describe());
+        if let Some(snippet) = source.and_then(|source| span::snippet(source, self.span)) {
+            rendered.push('\n');
+            rendered.push_str(&snippet);
+        }
+        if let Some(hint) = &self.hint {
+            rendered.push_str(&format!("\nhint: {}", hint));
+        }
+        rendered
+    }
+
+    fn describe(&self) -> String {
+        match &self.location {
+            Some(location) => format!("{} at {}: {}", self.severity, location, self.message),
+            None => format!("{}: {}", self.severity, self.message),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] {}", self.span.line, self.describe())?;
+        if let Some(hint) = &self.hint {
+            write!(f, " ({})", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+// every diagnostic in source order, followed by how many errors and warnings there were
+pub fn render_all(diagnostics: &[Diagnostic], file: &str, source: Option<&str>) -> String {
+    let mut rendered: Vec<String> = diagnostics.iter().map(|diagnostic| diagnostic.render(file, source)).collect();
+
+    let errors = diagnostics.iter().filter(|diagnostic| diagnostic.is_error()).count();
+    let warnings = diagnostics.len() - errors;
+    let mut counts = Vec::new();
+    if errors > 0 {
+        counts.push(plural(errors, "error"));
+    }
+    if warnings > 0 {
+        counts.push(plural(warnings, "warning"));
+    }
+    if diagnostics.len() > 1 {
+        rendered.push(format!("{} found.", counts.join(" and ")));
+    }
+    rendered.join("\n")
+}
+
+fn plural(count: usize, noun: &str) -> String {
+    match count {
+        1 => format!("1 {}", noun),
+        _ => format!("{} {}s", count, noun),
+    }
+}
@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+
+// number of globals the three byte operand of the long global instructions can address
+pub const MAX_GLOBALS: usize = 1 << 24;
+
+/*
+    Names of the global variables, each with the slot its value is stored in.
+
+    The compiler resolves every global to a slot, so the VM reads and writes globals by
+    array index instead of looking up their name. The table is owned by the VM and outlives
+    a single compile, so a name keeps its slot across REPL lines.
+    A slot is handed out the first time a name is seen, even before the global is defined,
+    the VM reports the global as undefined until a definition has run.
+*/
+#[derive(Debug, Default)]
+pub struct GlobalNames {
+    slots: HashMap<String, usize>,
+    names: Vec<String>,
+}
+
+impl GlobalNames {
+    pub fn new() -> Self {
+        GlobalNames::default()
+    }
+
+    // slot of the global, a new one is added for names not seen before
+    pub fn resolve(&mut self, name: &str) -> usize {
+        if let Some(slot) = self.slots.get(name) {
+            return *slot;
+        }
+
+        let slot = self.names.len();
+        self.slots.insert(name.to_string(), slot);
+        self.names.push(name.to_string());
+        slot
+    }
+
+    // slot of the global, None for names not seen before
+    pub fn slot(&self, name: &str) -> Option<usize> {
+        self.slots.get(name).copied()
+    }
+
+    pub fn name(&self, slot: usize) -> &str {
+        &self.names[slot]
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+}
@@ -0,0 +1,162 @@
+/*
+    Embedding API for quark.
+
+    Vm compiles and runs source on a VM that keeps its globals between calls, so a host can
+    load a script once and keep evaluating code against it. Errors are handed back as values,
+    nothing is written to stderr.
+*/
+mod bytecode;
+mod chunk;
+mod debug;
+mod value;
+mod vm;
+mod scanner;
+mod compiler;
+mod diagnostic;
+mod object;
+mod globals;
+mod span;
+mod verifier;
+
+use std::fmt;
+use std::io::Write;
+
+pub use crate::value::Value;
+pub use crate::object::{Object, ObjString, ObjList, ObjMap};
+pub use crate::diagnostic::{Diagnostic, Severity};
+pub use crate::vm::{RuntimeError, TraceFrame};
+pub use crate::bytecode::is_bytecode;
+pub use crate::span::Span;
+
+use crate::vm::{VM, InterpretResult};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Compile(Vec<Diagnostic>), // every diagnostic of the source, at least one is an error, nothing was run
+    Runtime(RuntimeError),
+    Global(String), // a global could not be set from the host
+    Bytecode(String), // a compiled script could not be loaded or saved
+    Output(String), // writing to a writer given by the host failed
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Compile(diagnostics) => {
+                let lines: Vec<String> = diagnostics.iter().map(|diagnostic| diagnostic.to_string()).collect();
+                write!(f, "{}", lines.join("\n"))
+            },
+            Error::Runtime(error) => write!(f, "{}", error),
+            Error::Global(message) | Error::Bytecode(message) | Error::Output(message) => write!(f, "Error: {}", message),
+        }
+    }
+}
+
+impl Error {
+    /*
+        The error as file:line:col followed by the message. The source line is shown with the
+        failing token underlined when the source is given.
+    */
+    pub fn render(&self, file: &str, source: Option<&str>) -> String {
+        match self {
+            Error::Compile(diagnostics) => diagnostic::render_all(diagnostics, file, source),
+            Error::Runtime(error) => error.render(file, source),
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Default)]
+pub struct Vm {
+    vm: VM,
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Vm::default()
+    }
+
+    /*
+        Compile and run the source.
+
+        Returns the value of the expression statement the source ends with, or void if it
+        ends in any other statement.
+    */
+    pub fn eval(&mut self, source: &str) -> Result<Value, Error> {
+        into_result(self.vm.interpret(source))
+    }
+
+    /*
+        Compile the source without running it and return every error and warning found in it,
+        for tools that show them in the editor. Nothing is written to the diagnostics writer.
+    */
+    pub fn check(&mut self, source: &str) -> Vec<Diagnostic> {
+        self.vm.check(source).1
+    }
+
+    // compile the source to the bytes of a .qkc file, without running it
+    pub fn compile(&mut self, source: &str) -> Result<Vec<u8>, Error> {
+        let function = self.vm.compile(source).map_err(Error::Compile)?;
+        self.vm.save(&function).map_err(Error::Bytecode)
+    }
+
+    // run a script compiled by compile, here or on another machine
+    pub fn eval_bytecode(&mut self, bytes: &[u8]) -> Result<Value, Error> {
+        let function = self.vm.load(bytes).map_err(Error::Bytecode)?;
+        into_result(self.vm.execute(function, None))
+    }
+
+    // compile the source and write the bytecode listing of every function in it, without running it
+    pub fn disassemble(&mut self, source: &str, out: &mut impl Write) -> Result<(), Error> {
+        let function = self.vm.compile(source).map_err(Error::Compile)?;
+        self.vm.disassemble(&function, out).map_err(|err| Error::Output(err.to_string()))
+    }
+
+    // where emit writes to, stdout by default
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.vm.set_output(Box::new(output));
+    }
+
+    // errors are also written here as they are returned, nowhere by default
+    pub fn set_diagnostics(&mut self, diagnostics: impl Write + 'static) {
+        self.vm.set_diagnostics(Box::new(diagnostics));
+    }
+
+    // name diagnostics use for the source in file:line:col, <script> by default
+    pub fn set_source_name(&mut self, name: &str) {
+        self.vm.set_source_name(name);
+    }
+
+    // value of the global, None if it has not been defined
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.vm.get_global(name)
+    }
+
+    // define the global or replace its value, scripts see it like any other global
+    pub fn set_global(&mut self, name: &str, value: impl Into<Value>) -> Result<(), Error> {
+        self.vm.set_global(name, value.into()).map_err(Error::Global)
+    }
+
+    /*
+        Register a Rust function as a global that scripts call like any other function.
+
+        Calls with a different number of arguments than arity are runtime errors, as is
+        an Err returned by the function, with the message it carries.
+    */
+    pub fn define_native<F>(&mut self, name: &str, arity: usize, function: F) -> Result<(), Error>
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        self.vm.define_native(name, arity, Box::new(function)).map_err(Error::Global)
+    }
+}
+
+fn into_result(result: InterpretResult) -> Result<Value, Error> {
+    match result {
+        InterpretResult::Ok(value) => Ok(value),
+        InterpretResult::CompileError(diagnostics) => Err(Error::Compile(diagnostics)),
+        InterpretResult::RuntimeError(error) => Err(Error::Runtime(error)),
+    }
+}
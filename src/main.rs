@@ -1,22 +1,14 @@
-mod chunk;
-mod debug;
-mod value;
-mod vm;
-mod scanner;
-mod compiler;
-mod object;
-
-//use crate::chunk::{Chunk, OpCode};
-//use crate::value::Value;
-use crate::vm::{VM, InterpretResult};
+use quark::{Vm, Value, Error};
 
 use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{env, io};
-use log::{debug};
 
 
 fn main() {
-    let mut vm: VM = VM::new();
+    let mut vm: Vm = Vm::new();
+    vm.set_diagnostics(io::stderr());
+    define_natives(&mut vm);
     
     let args: Vec<String> = env::args().collect();
     let argc = args.len() - 1;
@@ -25,6 +17,12 @@ fn main() {
         1 => {
             repl(&mut vm);
         },
+        5 if args[2] == "compile" && args[4] == "-o" => {
+            compile_file(&mut vm, &args[3], &args[5]);
+        },
+        3 if args[2] == "disasm" => {
+            disassemble_file(&mut vm, &args[3]);
+        },
         2 | 3 => {
             if argc == 3 && args[3] == "--debug" {
                 env::set_var("RUST_LOG", "debug");
@@ -41,12 +39,24 @@ fn main() {
         _ => {
             println!("Incorrect arguments.");
             println!("Usage: quark [path]");
+            println!("       quark compile [path] -o [output.qkc]");
+            println!("       quark disasm [path]");
             std::process::exit(64);
         },
     }
 }
 
-fn repl(vm: &mut VM) {
+// functions every script run by the command line can call
+fn define_natives(vm: &mut Vm) {
+    // seconds since the unix epoch
+    vm.define_native("clock", 0, |_| {
+        let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|err| err.to_string())?;
+        Ok(Value::from(elapsed.as_secs_f64()))
+    }).expect("natives are defined before any script runs");
+}
+
+fn repl(vm: &mut Vm) {
+    vm.set_source_name("<repl>");
     loop {
         print!("> ");
 
@@ -60,30 +70,95 @@ fn repl(vm: &mut VM) {
                 break;
             },
             _ => {
-                vm.interpret(&line);
+                // errors are already written to the diagnostics sink
+                let _ = vm.eval(&line);
             },
         }
 
     }
 }
 
-fn run_file(vm: &mut VM, path: &str) {
-    let buffer = std::fs::read_to_string(path).unwrap_or_else(|err| {
+// the file is run as source unless it starts like a compiled script
+fn run_file(vm: &mut Vm, path: &str) {
+    vm.set_source_name(path);
+    let buffer = std::fs::read(path).unwrap_or_else(|err| {
         eprintln!("Error reading file {}: {}", path, err);
         std::process::exit(74);
     });
-    match vm.interpret(&buffer) {
-        InterpretResult::CompileError => {
+
+    let result = match quark::is_bytecode(&buffer) {
+        true => vm.eval_bytecode(&buffer),
+        false => match String::from_utf8(buffer) {
+            Ok(source) => vm.eval(&source),
+            Err(_) => {
+                eprintln!("Error reading file {}: not valid UTF-8", path);
+                std::process::exit(74);
+            }
+        },
+    };
+
+    match result {
+        Err(Error::Compile(_)) => {
             eprintln!("Compile error in file: {}", path);
             std::process::exit(65);
         }
-        InterpretResult::RuntimeError => {
+        Err(Error::Bytecode(_)) => {
+            eprintln!("Could not load compiled file: {}", path);
+            std::process::exit(65);
+        }
+        Err(_) => {
             eprintln!("Runtime error in file: {}", path);
             std::process::exit(70);
         }
-        InterpretResult::Ok => {
+        Ok(_) => {
             println!("Execution completed successfully.");
             std::process::exit(0);
         }
     }
-}
\ No newline at end of file
+}
+// compile the source to bytecode that run_file can run later, without running it
+fn compile_file(vm: &mut Vm, path: &str, output: &str) {
+    vm.set_source_name(path);
+    let source = std::fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("Error reading file {}: {}", path, err);
+        std::process::exit(74);
+    });
+
+    let bytes = match vm.compile(&source) {
+        Ok(bytes) => bytes,
+        Err(Error::Compile(_)) => {
+            eprintln!("Compile error in file: {}", path);
+            std::process::exit(65);
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(70);
+        }
+    };
+
+    if let Err(err) = std::fs::write(output, bytes) {
+        eprintln!("Error writing file {}: {}", output, err);
+        std::process::exit(74);
+    }
+}
+
+// list the bytecode the source compiles to, without running it
+fn disassemble_file(vm: &mut Vm, path: &str) {
+    vm.set_source_name(path);
+    let source = std::fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("Error reading file {}: {}", path, err);
+        std::process::exit(74);
+    });
+
+    match vm.disassemble(&source, &mut io::stdout()) {
+        Ok(()) => {},
+        Err(Error::Compile(_)) => {
+            eprintln!("Compile error in file: {}", path);
+            std::process::exit(65);
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(74);
+        }
+    }
+}
@@ -1,38 +1,496 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::rc::Rc;
 
-#[derive(Clone, Hash, Debug)]
+use crate::chunk::Chunk;
+use crate::value::Value;
+
+#[derive(Clone, Debug)]
 pub struct ObjString {
-    string_rc : Rc<String>,
+    string_rc : Rc<str>,
 }
 
 impl ObjString {
-    pub fn from_str(s: &str) -> Self {
-        Self { string_rc: Rc::new(s.to_string())}
-        //Self{Rc::new(s.to_string())}
+    // a string that is not interned, the VM interns it when it is handed one. see Interner
+    pub fn new(s: &str) -> Self {
+        Self { string_rc: Rc::from(s)}
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.string_rc.as_ref()
+    }
+}
+
+// every string in the VM is interned, so equal strings share an allocation and comparing pointers is enough
+impl PartialEq for ObjString {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.string_rc, &other.string_rc)
+    }
+}
+
+impl Eq for ObjString {}
+
+impl Hash for ObjString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state);
+    }
+}
+
+/*
+    Table of the strings created by the compiler and the VM.
+
+    Interning returns the existing copy of a string if there is one, so equal strings
+    share one allocation. Strings nothing else refers to anymore are dropped from the
+    table whenever it has doubled in size since the last sweep.
+*/
+#[derive(Debug)]
+pub struct Interner {
+    strings: HashSet<Rc<str>>,
+    next_sweep: usize,
+}
+
+const INTERNER_INITIAL_SWEEP: usize = 1024;
+
+impl Interner {
+    pub fn new() -> Self {
+        Interner { strings: HashSet::new(), next_sweep: INTERNER_INITIAL_SWEEP }
+    }
+
+    pub fn intern(&mut self, s: &str) -> ObjString {
+        if let Some(string_rc) = self.strings.get(s) {
+            return ObjString { string_rc: Rc::clone(string_rc) };
+        }
+
+        if self.strings.len() >= self.next_sweep {
+            // the table holds the only reference to strings that are no longer used
+            self.strings.retain(|string_rc| Rc::strong_count(string_rc) > 1);
+            self.next_sweep = (self.strings.len() * 2).max(INTERNER_INITIAL_SWEEP);
+        }
+
+        let string_rc: Rc<str> = Rc::from(s);
+        self.strings.insert(Rc::clone(&string_rc));
+        ObjString { string_rc }
+    }
+}
+
+impl Default for Interner {
+    fn default() -> Self {
+        Interner::new()
+    }
+}
+
+/*
+    Function object produced by the compiler.
+
+    Every function owns the chunk of bytecode for its body. The top level script is compiled
+    into an implicit function with no name.
+*/
+#[derive(Debug)]
+pub struct ObjFunction {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    pub name: Option<ObjString>,
+}
+
+impl ObjFunction {
+    pub fn new(name: Option<ObjString>) -> Self {
+        ObjFunction { arity: 0, upvalue_count: 0, chunk: Chunk::new(), name }
+    }
+
+    pub fn display_name(&self) -> &str {
+        match &self.name {
+            Some(name) => name.as_str(),
+            None => "script",
+        }
+    }
+}
+
+/*
+    A variable captured by a closure.
+
+    While the variable is still alive on the VM stack the upvalue is open and points at its stack slot.
+    When the variable goes out of scope the value is moved into the upvalue and it becomes closed.
+*/
+#[derive(Debug)]
+pub enum ObjUpvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+/*
+    Runtime representation of a function together with the variables it captured.
+
+    Upvalues are shared, so closures capturing the same variable see each other's writes.
+*/
+#[derive(Debug)]
+pub struct ObjClosure {
+    pub function: Rc<ObjFunction>,
+    pub upvalues: Vec<Rc<RefCell<ObjUpvalue>>>,
+}
+
+impl ObjClosure {
+    pub fn new(function: Rc<ObjFunction>) -> Self {
+        let upvalues = Vec::with_capacity(function.upvalue_count);
+        ObjClosure { function, upvalues }
+    }
+}
+
+// host function callable from scripts. it gets the arguments of the call, an Err becomes a runtime error
+pub type NativeFn = dyn Fn(&[Value]) -> Result<Value, String>;
+
+/*
+    Function implemented in Rust and registered by the host.
+    It is called like any script function, the VM checks the arity before calling it.
+*/
+pub struct ObjNative {
+    pub name: String,
+    pub arity: usize,
+    pub function: Box<NativeFn>,
+}
+
+impl ObjNative {
+    pub fn new(name: &str, arity: usize, function: Box<NativeFn>) -> Self {
+        ObjNative { name: name.to_string(), arity, function }
+    }
+}
+
+// the function itself has nothing worth printing
+impl fmt::Debug for ObjNative {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ObjNative").field("name", &self.name).field("arity", &self.arity).finish()
+    }
+}
+
+/*
+    A class declared in the script. Calling it creates a new instance.
+*/
+#[derive(Debug)]
+pub struct ObjClass {
+    pub name: ObjString,
+    pub methods: HashMap<String, Rc<ObjClosure>>,
+}
+
+impl ObjClass {
+    pub fn new(name: ObjString) -> Self {
+        ObjClass { name, methods: HashMap::new() }
+    }
+}
+
+/*
+    Instance of a class. Fields are created on first assignment, so every instance
+    of the same class can have a different set of fields.
+*/
+#[derive(Debug)]
+pub struct ObjInstance {
+    pub class: Rc<RefCell<ObjClass>>,
+    pub fields: HashMap<String, Value>,
+}
+
+impl ObjInstance {
+    pub fn new(class: Rc<RefCell<ObjClass>>) -> Self {
+        ObjInstance { class, fields: HashMap::new() }
+    }
+}
+
+/*
+    A method accessed on an instance, remembering the instance it was accessed on
+    so that 'this' is still bound when the method is called later.
+*/
+#[derive(Debug)]
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: Rc<ObjClosure>,
+}
+
+/*
+    Growable list of values. Lists are shared by reference, so a list
+    mutated through one variable is changed for every variable holding it.
+*/
+#[derive(Debug)]
+pub struct ObjList {
+    pub items: Vec<Value>,
+}
+
+impl ObjList {
+    pub fn new(items: Vec<Value>) -> Self {
+        ObjList { items }
+    }
+}
+
+/*
+    Dictionary from keys to values that remembers the order keys were inserted in,
+    so enumerating keys and printing a map is deterministic.
+
+    entries holds the key value pairs in insertion order and
+    index maps every key to its position in entries.
+    Keys must be hashable values (see Value::is_hashable).
+*/
+#[derive(Debug, Default)]
+pub struct ObjMap {
+    entries: Vec<(Value, Value)>,
+    index: HashMap<MapKey, usize>,
+}
+
+/*
+    A value as a key of the index of a map.
+    Value can not be Eq, NaN is not equal to itself. Numbers are compared by their bits here
+    instead, with 0.0 and -0.0 as the same key like Value's hash has them, so keys are an equivalence.
+*/
+#[derive(Debug, Clone)]
+struct MapKey(Value);
+
+impl MapKey {
+    fn of(value: &Value) -> Self {
+        MapKey(value.clone())
+    }
+}
+
+impl PartialEq for MapKey {
+    fn eq(&self, other: &Self) -> bool {
+        let bits = |number: f64| if number == 0.0 { 0.0f64.to_bits() } else { number.to_bits() };
+        match (&self.0, &other.0) {
+            (Value::ValNumber(a), Value::ValNumber(b)) => bits(*a) == bits(*b),
+            (a, b) => a == b,
+        }
+    }
+}
+
+impl Eq for MapKey {}
+
+impl Hash for MapKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl ObjMap {
+    pub fn new() -> Self {
+        ObjMap::default()
+    }
+
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        self.index.get(&MapKey::of(key)).map(|position| &self.entries[*position].1)
+    }
+
+    pub fn contains_key(&self, key: &Value) -> bool {
+        self.index.contains_key(&MapKey::of(key))
+    }
+
+    pub fn insert(&mut self, key: Value, value: Value) {
+        match self.index.get(&MapKey::of(&key)) {
+            Some(position) => self.entries[*position].1 = value,
+            None => {
+                self.index.insert(MapKey::of(&key), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &Value) -> Option<Value> {
+        let position = self.index.remove(&MapKey::of(key))?;
+        let (_, value) = self.entries.remove(position);
+
+        // every entry after the removed one moved back by one
+        for (key, _) in &self.entries[position..] {
+            *self.index.get_mut(&MapKey::of(key)).unwrap() -= 1;
+        }
+
+        Some(value)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Value> {
+        self.entries.iter().map(|(key, _)| key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // remove every entry and hand them back in insertion order
+    pub fn take_entries(&mut self) -> Vec<(Value, Value)> {
+        self.index.clear();
+        std::mem::take(&mut self.entries)
+    }
+
+    // key at the given position in insertion order
+    pub fn key_at(&self, position: usize) -> Option<&Value> {
+        self.entries.get(position).map(|(key, _)| key)
+    }
+}
+
+/*
+    Numbers from start towards end, created with 'start..end' or 'start..=end'.
+    end is only part of the range when it is inclusive.
+    Ranges are immutable, step returns a new range counting in bigger or negative steps.
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct ObjRange {
+    pub start: f64,
+    pub end: f64,
+    pub step: f64,
+    pub inclusive: bool,
+}
+
+impl ObjRange {
+    pub fn new(start: f64, end: f64, inclusive: bool) -> Self {
+        ObjRange { start, end, step: 1.0, inclusive }
+    }
+
+    // number at the given position, None once it is past the end
+    pub fn get(&self, position: usize) -> Option<f64> {
+        let number = self.start + self.step * position as f64;
+        let in_range = match (self.step > 0.0, self.inclusive) {
+            (true, true) => number <= self.end,
+            (true, false) => number < self.end,
+            (false, true) => number >= self.end,
+            (false, false) => number > self.end,
+        };
+        in_range.then_some(number)
     }
 }
 
 /*
     Object to support custom data types in the VM
-    Currently, it only supports strings
+    Currently, it supports strings, functions, closures, native functions, classes, lists, maps and ranges
     More types can be added in the future
 */
+#[allow(clippy::enum_variant_names)]
 #[derive(Clone, Debug)]
 pub enum Object {
     ObjString(ObjString),
+    ObjFunction(Rc<ObjFunction>),
+    ObjClosure(Rc<ObjClosure>),
+    ObjNative(Rc<ObjNative>),
+    ObjClass(Rc<RefCell<ObjClass>>),
+    ObjInstance(Rc<RefCell<ObjInstance>>),
+    ObjBoundMethod(Rc<ObjBoundMethod>),
+    ObjList(Rc<RefCell<ObjList>>),
+    ObjMap(Rc<RefCell<ObjMap>>),
+    ObjRange(Rc<ObjRange>),
 }
 
 impl Object{
-    // pub fn from_str(s: &str) -> Self {
+    // pub fn new(s: &str) -> Self {
     //     Self::ObjString(Rc::new(s.to_string()))
     // }
 
     //get refrence to the string data inside the Object::ObjString variant
     pub fn get_object_data(&self) -> Option<&str> {
         match self {
-            Object::ObjString(obj_string) => Some(obj_string.string_rc.as_ref())
-           // Some(string_rc.as_ref()),
+            Object::ObjString(obj_string) => Some(obj_string.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn to_display_string(&self) -> String {
+        self.display_string(&mut HashSet::new())
+    }
+
+    /*
+        printing holds the addresses of the lists and maps being printed around this object.
+        A collection can hold itself, it is printed as [...] or {...} where it comes up again inside itself.
+    */
+    fn display_string(&self, printing: &mut HashSet<*const ()>) -> String {
+        match self {
+            Object::ObjString(obj_string) => obj_string.as_str().to_string(),
+            Object::ObjFunction(function) => function_display_string(function),
+            Object::ObjClosure(closure) => function_display_string(&closure.function),
+            Object::ObjNative(native) => format!("<native fn {}>", native.name),
+            Object::ObjClass(class) => format!("<class {}>", class.borrow().name.as_str()),
+            Object::ObjInstance(instance) => format!("<{} instance>", instance.borrow().class.borrow().name.as_str()),
+            Object::ObjBoundMethod(bound) => function_display_string(&bound.method.function),
+            Object::ObjList(list) => {
+                let address = Rc::as_ptr(list) as *const ();
+                if !printing.insert(address) {
+                    return String::from("[...]");
+                }
+                let items: Vec<String> = list.borrow().items.iter().map(|item| element_display_string(item, printing)).collect();
+                printing.remove(&address);
+                format!("[{}]", items.join(", "))
+            },
+            Object::ObjMap(map) => {
+                let address = Rc::as_ptr(map) as *const ();
+                if !printing.insert(address) {
+                    return String::from("{...}");
+                }
+                let entries: Vec<String> = map.borrow().entries.iter()
+                    .map(|(key, value)| format!("{}: {}", element_display_string(key, printing), element_display_string(value, printing)))
+                    .collect();
+                printing.remove(&address);
+                format!("{{{}}}", entries.join(", "))
+            },
+            Object::ObjRange(range) => {
+                let operator = if range.inclusive { "..=" } else { ".." };
+                let bounds = format!("{}{}{}", Value::ValNumber(range.start).to_display_string(), operator,
+                    Value::ValNumber(range.end).to_display_string());
+                match range.step == 1.0 {
+                    true => bounds,
+                    false => format!("({}).step({})", bounds, Value::ValNumber(range.step).to_display_string()),
+                }
+            },
+        }
+    }
+}
+
+impl PartialEq for Object {
+    /*
+        Every object compares by identity. For strings that only matches comparing their text
+        because every string is interned, two strings with the same text are the same ObjString.
+    */
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Object::ObjString(a), Object::ObjString(b)) => a == b,
+            (Object::ObjFunction(a), Object::ObjFunction(b)) => Rc::ptr_eq(a, b),
+            (Object::ObjClosure(a), Object::ObjClosure(b)) => Rc::ptr_eq(a, b),
+            (Object::ObjNative(a), Object::ObjNative(b)) => Rc::ptr_eq(a, b),
+            (Object::ObjClass(a), Object::ObjClass(b)) => Rc::ptr_eq(a, b),
+            (Object::ObjInstance(a), Object::ObjInstance(b)) => Rc::ptr_eq(a, b),
+            (Object::ObjBoundMethod(a), Object::ObjBoundMethod(b)) => Rc::ptr_eq(a, b),
+            (Object::ObjList(a), Object::ObjList(b)) => Rc::ptr_eq(a, b),
+            (Object::ObjMap(a), Object::ObjMap(b)) => Rc::ptr_eq(a, b),
+            (Object::ObjRange(a), Object::ObjRange(b)) => Rc::ptr_eq(a, b),
+            (_, _) => false,
+        }
+    }
+}
+
+impl Hash for Object {
+    // consistent with PartialEq: an interned string has one address per text, so hashing its content agrees
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Object::ObjString(obj_string) => obj_string.as_str().hash(state),
+            Object::ObjFunction(function) => Rc::as_ptr(function).hash(state),
+            Object::ObjClosure(closure) => Rc::as_ptr(closure).hash(state),
+            Object::ObjNative(native) => Rc::as_ptr(native).hash(state),
+            Object::ObjClass(class) => Rc::as_ptr(class).hash(state),
+            Object::ObjInstance(instance) => Rc::as_ptr(instance).hash(state),
+            Object::ObjBoundMethod(bound) => Rc::as_ptr(bound).hash(state),
+            Object::ObjList(list) => Rc::as_ptr(list).hash(state),
+            Object::ObjMap(map) => Rc::as_ptr(map).hash(state),
+            Object::ObjRange(range) => Rc::as_ptr(range).hash(state),
         }
-        
+    }
+}
+
+fn function_display_string(function: &ObjFunction) -> String {
+    match function.name {
+        Some(_) => format!("<fn {}>", function.display_name()),
+        None => String::from("<script>"),
+    }
+}
+
+// strings inside a collection are quoted so they can be told apart from other values
+fn element_display_string(value: &Value, printing: &mut HashSet<*const ()>) -> String {
+    match value {
+        Value::ValObject(Object::ObjString(string)) => format!("\"{}\"", string.as_str()),
+        Value::ValObject(object) => object.display_string(printing),
+        _ => value.to_display_string(),
     }
 }
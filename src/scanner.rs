@@ -1,13 +1,17 @@
 
 use std::str;
 
+use crate::span::Span;
+
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum TokenType {
     //single-character tokens
     LeftParen, RightParen,
     LeftBrace, RightBrace,
-    Comma, Dot, Minus, Plus,
+    LeftBracket, RightBracket,
+    Comma, Dot, Colon, Minus, Plus,
     Semicolon, Slash, Star,
+    DotDot, DotDotEqual, // '..' and '..=' build ranges
 
     //one or two character tokens
     Bang, BangEqual, 
@@ -17,12 +21,16 @@ pub enum TokenType {
 
     //literals
     Identifier, String, Number,
+    Interpolation, // part of a string literal ending at '${', an expression follows
+    RawString, // r"..." - no escapes or interpolation
+    MultilineString, // """...""" - common indentation is removed
 
     //keywords
     And, Else, False, 
     Function, If, Void, Or,
     Emit, Return, True, Create, 
-    While, For,
+    While, For, Class, This, Super, Const,
+    Break, Continue, In,
 
     Error, Eof,
 
@@ -42,6 +50,7 @@ impl From<TokenType> for usize {
     - token_type: type of the token (e.g. identifier, number, string, etc.)
     - lexeme: the actual text of the token, is a reference to a slice of the source code
     - line: the line number in the source code where the token was found
+    - span: where the token starts and ends in the source. error tokens span the text that could not be scanned
 */
 #[derive(Clone, Copy, Debug)]
 pub struct Token<'a> {
@@ -50,44 +59,70 @@ pub struct Token<'a> {
     // length: i32,
     pub lexeme: &'a [u8],
     pub line: i32,
+    pub span: Span,
 }
 
 impl <'a> Token <'a> {
     pub fn new() -> Self {
-        Token { token_type: TokenType::Eof, lexeme: b"", line: -1}
-    }
-
-    fn make_token(token_type: TokenType, lexeme: &[u8], line: i32) -> Token{
-        Token {token_type, lexeme, line}
+        Token { token_type: TokenType::Eof, lexeme: b"", line: -1, span: Span::default() }
     }
 
-    fn make_error_token(message: &str, line: i32) -> Token {
-        Token {token_type: TokenType::Error, lexeme: message.as_bytes(), line: line}
-    }
-
-    pub fn to_lexeme(&self) -> &str {
-        str::from_utf8(self.lexeme.clone()).unwrap()
+    pub fn to_lexeme(self) -> &'a str {
+        str::from_utf8(self.lexeme).unwrap()
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Scanner <'a> {
     source: &'a [u8],
     start: usize, // start of current lexeme
     current: usize, // current character of current lexeme
     line: i32,
+    column: i32, // column of the current character, in characters
+    start_column: i32, // column the current lexeme starts at
+    // one entry per string interpolation we are inside of, counting the '{' opened within it.
+    // a '}' seen when the count is zero ends the interpolation and the string continues
+    interpolation_braces: Vec<usize>,
 }
 
 impl<'a> Scanner <'a> {
-    pub fn new(source: &str) -> Scanner {
-        Scanner{source: source.as_bytes(), start: 0, current: 0, line: 1}
+    pub fn new(source: &str) -> Scanner<'_> {
+        Scanner{source: source.as_bytes(), start: 0, current: 0, line: 1, column: 1, start_column: 1, interpolation_braces: Vec::new()}
+    }
+
+    // the next lexeme starts at the current character
+    fn start_lexeme(&mut self) {
+        self.start = self.current;
+        self.start_column = self.column;
+    }
+
+    // token of the current lexeme
+    fn make_token(&self, token_type: TokenType) -> Token<'a> {
+        Token { token_type, lexeme: &self.source[self.start..self.current], line: self.line, span: self.span() }
+    }
+
+    fn make_error_token(&self, message: &'static str) -> Token<'a> {
+        Token { token_type: TokenType::Error, lexeme: message.as_bytes(), line: self.line, span: self.span() }
+    }
+
+    /*
+        Span of the current lexeme.
+        All the newlines up to the current character are counted in line, so the line the lexeme
+        starts on is found by taking away the ones inside it.
+    */
+    fn span(&self) -> Span {
+        let end = self.current.min(self.source.len());
+        let lexeme = &self.source[self.start..end];
+        let newlines = lexeme.iter().filter(|byte| **byte == b'\n').count() as i32;
+
+        Span { line: self.line - newlines, column: self.start_column, start: self.start, end }
     }
 
     /*
       Returns true if the scanner has reached the end of the source code.
     */
     fn is_at_end(&self) -> bool{
-        return self.current == self.source.len()
+        self.current >= self.source.len()
     }
 
     /*
@@ -100,29 +135,24 @@ impl<'a> Scanner <'a> {
         self.current += 1;
         // print!(" {:?} ", str::from_utf8(&self.source[self.start..self.current]).unwrap());
 
+        // a newline starts the column over, continuation bytes of multi-byte characters do not start a column
+        match self.source.get(self.current - 1) {
+            Some(b'\n') => self.column = 1,
+            Some(byte) if (byte & 0xc0) != 0x80 => self.column += 1,
+            _ => (),
+        }
+
         self.source.get(self.current - 1)
     }
 
     fn match_(&mut self, _expected: u8) -> bool {
         match self.source.get(self.current) {
-            None => false,
-            Some(c) => {
-                if c == &_expected {
-                    self.current += 1;
-                    true
-                } else {
-                    false
-                }
-            },
+            Some(actual) if actual == &_expected => {
+                self.advance();
+                true
+            }
+            _ => false,
         }
-
-        // match self.source.get(self.current) {
-        //     Some(actual) if actual == &_expected => {
-        //         self.current += 1;
-        //         true
-        //     }
-        //     _ => false,
-        // }
     }
 
     fn peek(&self) -> Option<&u8> {
@@ -133,7 +163,16 @@ impl<'a> Scanner <'a> {
         self.source.get(self.current + 1)
     }
 
-    fn skip_whitespace(&mut self) {
+    /*
+        Skip whitespace and comments before the next token.
+
+        // comments run to the end of the line.
+        /* ... */ comments can span lines and nest, so commenting out code that already
+        has block comments in it works.
+
+        Returns an error token if a block comment is never closed.
+    */
+    fn skip_whitespace(&mut self) -> Option<Token<'a>> {
         loop {
             match self.peek() {
                 Some(b' ' | b'\t' | b'\r') => {
@@ -143,28 +182,141 @@ impl<'a> Scanner <'a> {
                     self.advance();
                     self.line += 1;
                 },
-                _ => break,
+                Some(b'/') if self.peek_next() == Some(&b'/') => {
+                    // the newline is left for the next iteration so the line is counted
+                    while self.peek().is_some() && self.peek() != Some(&b'\n') {
+                        self.advance();
+                    }
+                },
+                Some(b'/') if self.peek_next() == Some(&b'*') => {
+                    if let Some(error) = self.block_comment() {
+                        return Some(error);
+                    }
+                },
+                _ => return None,
             }
         }
     }
 
+    fn block_comment(&mut self) -> Option<Token<'a>> {
+        let start_line = self.line;
+        let mut depth = 0;
+        // the error for an unterminated comment spans all of it
+        self.start_lexeme();
+
+        loop {
+            match (self.peek(), self.peek_next()) {
+                (None, _) => {
+                    let mut error = self.make_error_token("Unterminated block comment");
+                    error.line = start_line;
+                    return Some(error);
+                },
+                (Some(b'/'), Some(b'*')) => {
+                    self.advance();
+                    depth += 1;
+                },
+                (Some(b'*'), Some(b'/')) => {
+                    self.advance();
+                    depth -= 1;
+                    if depth == 0 {
+                        self.advance();
+                        return None;
+                    }
+                },
+                (Some(b'\n'), _) => self.line += 1,
+                (Some(_), _) => (),
+            }
+            self.advance();
+        }
+    }
+
+    /*
+        Scan a string literal, or the rest of one after an interpolated expression.
+
+        If '${' is found, the text up to and including it becomes an Interpolation token
+        and scanning goes back to normal tokens until the matching '}'.
+    */
     fn string(&mut self) -> Token<'a>{
         while self.peek() != Some(&b'"') && !self.is_at_end() {
             if self.peek() == Some(&b'\n') {
                 self.line += 1;
             }
 
+            if self.peek() == Some(&b'$') && self.peek_next() == Some(&b'{') {
+                self.advance();
+                self.advance();
+                self.interpolation_braces.push(0);
+                return self.make_token(TokenType::Interpolation);
+            }
+
+            // the escaped character can not end the string or start an interpolation.
+            // the compiler decodes the escape sequence
+            if self.peek() == Some(&b'\\') && self.peek_next().is_some() {
+                self.advance();
+                if self.peek() == Some(&b'\n') {
+                    self.line += 1;
+                }
+            }
+
             self.advance();
         }
 
         if self.is_at_end() {
-            Token::make_error_token("Unterminated String", self.line)
+            self.make_error_token("Unterminated String")
         } else {
             self.advance();
-            Token::make_token(TokenType::String, &self.source[self.start..self.current], self.line)
+            self.make_token(TokenType::String)
         }
     }
 
+    // r"..." - everything up to the next quote is taken as is
+    fn raw_string(&mut self) -> Token<'a> {
+        // opening quote
+        self.advance();
+
+        while self.peek() != Some(&b'"') && !self.is_at_end() {
+            if self.peek() == Some(&b'\n') {
+                self.line += 1;
+            }
+            self.advance();
+        }
+
+        if self.is_at_end() {
+            return self.make_error_token("Unterminated raw string");
+        }
+
+        self.advance();
+        self.make_token(TokenType::RawString)
+    }
+
+    // """...""" - the opening quote has already been consumed
+    fn multiline_string(&mut self) -> Token<'a> {
+        self.advance();
+        self.advance();
+
+        loop {
+            match self.peek() {
+                None => return self.make_error_token("Unterminated multi-line string"),
+                Some(b'"') if self.peek_next() == Some(&b'"') && self.source.get(self.current + 2) == Some(&b'"') => break,
+                Some(b'\n') => self.line += 1,
+                // skip the escaped character so \" does not end the string
+                Some(b'\\') if self.peek_next().is_some() => {
+                    self.advance();
+                    if self.peek() == Some(&b'\n') {
+                        self.line += 1;
+                    }
+                },
+                Some(_) => (),
+            }
+            self.advance();
+        }
+
+        self.advance();
+        self.advance();
+        self.advance();
+        self.make_token(TokenType::MultilineString)
+    }
+
     fn number(&mut self) -> Token<'a> {
         while self.peek().is_some() && self.peek().unwrap().is_ascii_digit() {
             self.advance();
@@ -178,7 +330,7 @@ impl<'a> Scanner <'a> {
             }
         }
 
-        Token::make_token(TokenType::Number, &self.source[self.start..self.current], self.line)
+        self.make_token(TokenType::Number)
     }
 
     fn identifier(&mut self) -> Token<'a> {
@@ -186,7 +338,7 @@ impl<'a> Scanner <'a> {
             self.advance();
         }
 
-        Token::make_token(self.identifier_type(), &self.source[self.start..self.current], self.line)
+        self.make_token(self.identifier_type())
     }
     
     /*
@@ -216,7 +368,19 @@ impl<'a> Scanner <'a> {
     fn identifier_type(&self) -> TokenType {
         match self.source[self.start] {
             b'a' => self.check_keyword(1, "nd", TokenType::And),
-            b'c' => self.check_keyword(1, "reate", TokenType::Create),
+            b'b' => self.check_keyword(1, "reak", TokenType::Break),
+            b'c' => {
+                match self.source.get(self.start + 1) {
+                    Some(b'l') => self.check_keyword(2, "ass", TokenType::Class),
+                    Some(b'o') => match self.source.get(self.start + 3) {
+                        Some(b's') => self.check_keyword(2, "nst", TokenType::Const),
+                        Some(b't') => self.check_keyword(2, "ntinue", TokenType::Continue),
+                        _ => TokenType::Identifier,
+                    },
+                    Some(b'r') => self.check_keyword(2, "eate", TokenType::Create),
+                    _ => TokenType::Identifier,
+                }
+            },
             b'e' => {
                 match self.source.get(self.start + 1) {
                     Some(b'l') => self.check_keyword(2, "se", TokenType::Else),
@@ -232,10 +396,23 @@ impl<'a> Scanner <'a> {
                     _ => TokenType::Identifier,
                 }
             },
-            b'i' => self.check_keyword(1, "f", TokenType::If),
+            b'i' => {
+                match self.source.get(self.start + 1) {
+                    Some(b'f') => self.check_keyword(2, "", TokenType::If),
+                    Some(b'n') => self.check_keyword(2, "", TokenType::In),
+                    _ => TokenType::Identifier,
+                }
+            },
             b'o' => self.check_keyword(1, "r", TokenType::Or),
-            b'r' => self.check_keyword(1, "reate", TokenType::Return),
-            b't' => self.check_keyword(1, "rue", TokenType::True),
+            b'r' => self.check_keyword(1, "eturn", TokenType::Return),
+            b's' => self.check_keyword(1, "uper", TokenType::Super),
+            b't' => {
+                match self.source.get(self.start + 1) {
+                    Some(b'h') => self.check_keyword(2, "is", TokenType::This),
+                    Some(b'r') => self.check_keyword(2, "ue", TokenType::True),
+                    _ => TokenType::Identifier,
+                }
+            },
             b'v' => self.check_keyword(1, "oid", TokenType::Void),
             b'w' => self.check_keyword(1, "hile", TokenType::While),
             _ => TokenType::Identifier,
@@ -243,27 +420,62 @@ impl<'a> Scanner <'a> {
         }
     }
 
+    // the token scan_token would return next, without consuming it
+    pub fn peek_token(&self) -> Token<'a> {
+        self.clone().scan_token()
+    }
+
     /*
         Scans a single token 
     */
     pub fn scan_token(&mut self) -> Token<'a> {
-        self.skip_whitespace();
-        self.start = self.current;
+        if let Some(error) = self.skip_whitespace() {
+            return error;
+        }
+        self.start_lexeme();
 
         if self.is_at_end() {
-            return Token::make_token(TokenType::Eof, &self.source[self.start..self.current], self.line);
+            return self.make_token(TokenType::Eof);
         }
 
-        let token_type = match self.advance() {
+        let token_type = match self.advance().copied() {
             None => TokenType::Eof,
             Some(c) =>  match c {
                 b'(' => TokenType::LeftParen,
                 b')' => TokenType::RightParen,
-                b'{' => TokenType::LeftBrace,
-                b'}' => TokenType::RightBrace,
+                b'{' => {
+                    if let Some(braces) = self.interpolation_braces.last_mut() {
+                        *braces += 1;
+                    }
+                    TokenType::LeftBrace
+                },
+                b'[' => TokenType::LeftBracket,
+                b']' => TokenType::RightBracket,
+                b'}' => {
+                    match self.interpolation_braces.last_mut() {
+                        // end of an interpolated expression, the string literal carries on
+                        Some(0) => {
+                            self.interpolation_braces.pop();
+                            self.start_lexeme();
+                            return self.string();
+                        },
+                        Some(braces) => *braces -= 1,
+                        None => (),
+                    }
+                    TokenType::RightBrace
+                },
                 b';' => TokenType::Semicolon,
                 b',' => TokenType::Comma,
-                b'.' => TokenType::Dot,
+                b'.' => {
+                    if !self.match_(b'.') {
+                        TokenType::Dot
+                    } else if self.match_(b'=') {
+                        TokenType::DotDotEqual
+                    } else {
+                        TokenType::DotDot
+                    }
+                },
+                b':' => TokenType::Colon,
                 b'-' => TokenType::Minus,
                 b'+' => TokenType::Plus,
                 b'/' => TokenType::Slash,
@@ -297,19 +509,25 @@ impl<'a> Scanner <'a> {
                     }
                 },  
                 b'"' => {
+                    if self.peek() == Some(&b'"') && self.peek_next() == Some(&b'"') {
+                        return self.multiline_string();
+                    }
                     return self.string();
                 }  
+                b'r' if self.peek() == Some(&b'"') => {
+                    return self.raw_string();
+                }
                 c if c.is_ascii_digit() => {
                     return self.number();
                 }
-                c if c.is_ascii_alphanumeric() || c == &b'_' => {
+                c if c.is_ascii_alphanumeric() || c == b'_' => {
                     return self.identifier();
                 },
-                _ => return Token::make_error_token("Unexpected character.", self.line),
+                _ => return self.make_error_token("Unexpected character."),
             },
         };
 
-        Token::make_token(token_type, &self.source[self.start..self.current], self.line)
+        self.make_token(token_type)
 
         
 
@@ -0,0 +1,42 @@
+/*
+    Where a token is in the source.
+
+    line and column count from 1, the column in characters so it matches what an editor shows.
+    start and end are byte offsets into the source, end is exclusive.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub line: i32,
+    pub column: i32,
+    pub start: usize,
+    pub end: usize,
+}
+
+/*
+    The source line the span starts on, followed by a line with a caret under every character of
+    the span, both indented behind the line number. A span running over several lines is
+    underlined to the end of its first line.
+
+    Returns None when the span is not in the source, for example when it was compiled from a
+    different version of it.
+*/
+pub fn snippet(source: &str, span: Span) -> Option<String> {
+    if span.start > source.len() || !source.is_char_boundary(span.start) {
+        return None;
+    }
+
+    let line_start = source[..span.start].rfind('\n').map_or(0, |newline| newline + 1);
+    let line_end = source[span.start..].find('\n').map_or(source.len(), |newline| span.start + newline);
+    let line = source[line_start..line_end].trim_end_matches('\r');
+
+    // tabs are kept so the caret lines up however wide the terminal shows them
+    let indent: String = source[line_start..span.start].chars()
+        .map(|character| if character == '\t' { '\t' } else { ' ' })
+        .collect();
+    let underlined_end = span.end.clamp(span.start, line_start + line.len());
+    let width = source.get(span.start..underlined_end).map_or(0, |text| text.chars().count()).max(1);
+
+    let number = span.line.to_string();
+    let gutter = " ".repeat(number.len());
+    Some(format!("{} | {}\n{} | {}{}", number, line, gutter, indent, "^".repeat(width)))
+}
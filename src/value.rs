@@ -1,11 +1,13 @@
+use std::hash::{Hash, Hasher};
 use std::ops::{Neg, Add, Sub, Mul, Div, Not};
 
-use crate::object::Object;
+use crate::object::{Object, ObjString};
 
 /*
     Value is an enum that represents the different types of values that can be stored in the virtual machine
     It can be a boolean, number, void (nil), or an object
 */
+#[allow(clippy::enum_variant_names)]
 #[derive(Clone, Debug)]
 pub enum Value {
     ValBool(bool),
@@ -14,29 +16,35 @@ pub enum Value {
     ValObject(Object)
 }
 
-impl From<Value> for bool {
-    fn from(value: Value) -> bool {
+impl TryFrom<Value> for bool {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<bool, String> {
         match value{
-            Value::ValBool(bool_val) => bool_val,
-            _ => panic!("Error. Value is not boolean"),
+            Value::ValBool(bool_val) => Ok(bool_val),
+            _ => Err(String::from("Value is not boolean")),
         }
     }
 }
 
-impl From<Value> for f64 {
-    fn from(value: Value) -> f64 {
+impl TryFrom<Value> for f64 {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<f64, String> {
         match value {
-            Value::ValNumber(num_val) => num_val,
-            _ => panic!("Error. Value is not numeric"),
+            Value::ValNumber(num_val) => Ok(num_val),
+            _ => Err(String::from("Value is not numeric")),
         }
     }
 }
 
-impl From<Value> for () {
-    fn from(value: Value) -> () {
+impl TryFrom<Value> for () {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<(), String> {
         match value {
-            Value::ValVoid(void_val) => void_val,
-            _ => panic!("Error. Value is not nill"),
+            Value::ValVoid(()) => Ok(()),
+            _ => Err(String::from("Value is not void")),
         }
     }
 }
@@ -59,137 +67,172 @@ impl From<()> for Value {
     }
 }
 
+/*
+    Strings made outside the VM are not interned. The VM interns them when they are passed to it,
+    until then a string made this way is only equal to itself. Compare with as_str on the host.
+*/
+impl From<&str> for Value {
+    fn from(str_val: &str) -> Value {
+        Value::ValObject(Object::ObjString(ObjString::new(str_val)))
+    }
+}
+
+/*
+    The operators return an error message instead of a value when the operands have the wrong type,
+    the VM turns it into a runtime error.
+*/
 impl Neg for Value {
-    type Output = Value;
+    type Output = Result<Value, String>;
 
-    fn neg(self) -> Value {
+    fn neg(self) -> Result<Value, String> {
         match self {
-            Value::ValNumber(num) => Value::ValNumber(-num),
-            _ => panic!("Error. Negating a non numeric value is not possible"),
+            Value::ValNumber(num) => Ok(Value::ValNumber(-num)),
+            _ => Err(String::from("Operand must be a number")),
         }
     }
 }
 
 impl Add for Value {
-    type Output = Value;
+    type Output = Result<Value, String>;
 
-    fn add(self, rhs: Value) -> Value {
+    fn add(self, rhs: Value) -> Result<Value, String> {
         match (self, rhs) {
-            (Value::ValNumber(a), Value::ValNumber(b)) => Value::ValNumber(a + b),
-            (Value::ValNumber(_), _) => panic!("Error. Invalid right argument"),
-            (_, Value::ValNumber(_)) => panic!("Error. Invalid left argument"),
-            (_, _) => panic!("Error. Invalid arguments for arithmetic addition"),
+            (Value::ValNumber(a), Value::ValNumber(b)) => Ok(Value::ValNumber(a + b)),
+            (Value::ValNumber(_), _) => Err(String::from("Invalid right argument")),
+            (_, Value::ValNumber(_)) => Err(String::from("Invalid left argument")),
+            (_, _) => Err(String::from("Invalid arguments for arithmetic addition")),
         }
     }
 }
 
 
 impl Sub for Value {
-    type Output = Value;
+    type Output = Result<Value, String>;
 
-    fn sub(self, rhs: Value) -> Value {
+    fn sub(self, rhs: Value) -> Result<Value, String> {
         match (self, rhs) {
-            (Value::ValNumber(a), Value::ValNumber(b)) => Value::ValNumber(a - b),
-            (Value::ValNumber(_), _) => panic!("Error. Invalid right argument"),
-            (_, Value::ValNumber(_)) => panic!("Error. Invalid left argument"),
-            (_, _) => panic!("Error. Invalid arguments for arithmetic subtraction"),
+            (Value::ValNumber(a), Value::ValNumber(b)) => Ok(Value::ValNumber(a - b)),
+            (Value::ValNumber(_), _) => Err(String::from("Invalid right argument")),
+            (_, Value::ValNumber(_)) => Err(String::from("Invalid left argument")),
+            (_, _) => Err(String::from("Invalid arguments for arithmetic subtraction")),
         }
     }
 }
 
 
 impl Mul for Value {
-    type Output = Value;
+    type Output = Result<Value, String>;
 
-    fn mul(self, rhs: Value) -> Value {
+    fn mul(self, rhs: Value) -> Result<Value, String> {
         match (self, rhs) {
-            (Value::ValNumber(a), Value::ValNumber(b)) => Value::ValNumber(a * b),
-            (Value::ValNumber(_), _) => panic!("Error. Invalid right argument"),
-            (_, Value::ValNumber(_)) => panic!("Error. Invalid left argument"),
-            (_, _) => panic!("Error. Invalid arguments for arithmetic multiplication"),
+            (Value::ValNumber(a), Value::ValNumber(b)) => Ok(Value::ValNumber(a * b)),
+            (Value::ValNumber(_), _) => Err(String::from("Invalid right argument")),
+            (_, Value::ValNumber(_)) => Err(String::from("Invalid left argument")),
+            (_, _) => Err(String::from("Invalid arguments for arithmetic multiplication")),
         }
     }
 }
 
 
 impl Div for Value {
-    type Output = Value;
+    type Output = Result<Value, String>;
 
-    fn div(self, rhs: Value) -> Value {
+    fn div(self, rhs: Value) -> Result<Value, String> {
         match (self, rhs) {
-            (Value::ValNumber(_), Value::ValNumber(0.0)) => panic!("Error. Cannot divide by zero"),
-            (Value::ValNumber(a), Value::ValNumber(b)) => Value::ValNumber(a / b),
-            (Value::ValNumber(_), _) => panic!("Error. Invalid right argument"),
-            (_, Value::ValNumber(_)) => panic!("Error. Invalid left argument"),
-            (_, _) => panic!("Error. Invalid arguments for arithmetic addition"),
+            (Value::ValNumber(_), Value::ValNumber(0.0)) => Err(String::from("Cannot divide by zero")),
+            (Value::ValNumber(a), Value::ValNumber(b)) => Ok(Value::ValNumber(a / b)),
+            (Value::ValNumber(_), _) => Err(String::from("Invalid right argument")),
+            (_, Value::ValNumber(_)) => Err(String::from("Invalid left argument")),
+            (_, _) => Err(String::from("Invalid arguments for arithmetic division")),
         }
     }
 }
 
 impl Not for Value {
-    type Output = Value;
+    type Output = Result<Value, String>;
 
-    fn not(self) -> Value {
+    fn not(self) -> Result<Value, String> {
         match self {
-            Value::ValBool(boolean) => Value::ValBool(!boolean),
-            Value::ValVoid(()) => Value::ValBool(true), // nil is considered true in this context
-            _ => panic!("Error. Invalid argument for not operator"),
+            Value::ValBool(boolean) => Ok(Value::ValBool(!boolean)),
+            Value::ValVoid(()) => Ok(Value::ValBool(true)), // nil is considered true in this context
+            _ => Err(String::from("Operand must be a boolean")),
         }
     }
 }
 
+// only numbers are ordered, comparing anything else gives None
 impl PartialOrd for Value {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         match (self, other) {
             (Value::ValNumber(a), Value::ValNumber(b)) => a.partial_cmp(b),
-            _ => panic!("Error. Using non numeric values for comparison."),
+            _ => None,
         }
     }
 }
 
+/*
+    Equality and hashing have to agree so values can be used as map keys:
+    values that are equal must hash the same.
+
+    Numbers compare as f64, so NaN is not equal to itself and Value is not Eq.
+    Maps key their index with object::MapKey, which is.
+*/
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (Value::ValObject(object), Value::ValObject(other_object)) => object.get_object_data() == other_object.get_object_data(),
+            (Value::ValObject(object), Value::ValObject(other_object)) => object == other_object,
             (Value::ValBool(val1), Value::ValBool(val2)) => val1 == val2,
             (Value::ValNumber(val1), Value::ValNumber(val2)) => val1 == val2,
+            (Value::ValVoid(()), Value::ValVoid(())) => true,
             (_, _) => false,
         }
     }
 }
 
-impl Value {
-    pub fn is_number(&self) -> bool {
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+
         match self {
-            Value::ValNumber(_) => true,
-            _ => false,
+            Value::ValBool(boolean) => boolean.hash(state),
+            Value::ValVoid(()) => (),
+            // 0.0 and -0.0 are equal so they need the same hash
+            Value::ValNumber(num) => {
+                let num = if *num == 0.0 { 0.0 } else { *num };
+                num.to_bits().hash(state);
+            },
+            Value::ValObject(object) => object.hash(state),
         }
     }
+}
+
+impl Value {
+    pub fn is_number(&self) -> bool {
+        matches!(self, Value::ValNumber(_))
+    }
 
     pub fn is_bool(&self) -> bool {
-        match self {
-            Value::ValBool(_) | Value::ValVoid(_)=> true,
-            _ => false,
-        }
+        matches!(self, Value::ValBool(_) | Value::ValVoid(_))
     }
 
     pub fn is_true(&self) -> bool {
-        match self {
-            Value::ValBool(true) => true,
-            _ => false,
-        }
+        matches!(self, Value::ValBool(true))
     }
     
     pub fn is_false(&self) -> bool {
-        match self {
-            Value::ValBool(false) => true,
-            _ => false,
-        }
+        matches!(self, Value::ValBool(false))
     }
 
     pub fn is_object(&self) -> bool {
+        matches!(self, Value::ValObject(_))
+    }
+
+    // only strings, numbers (other than NaN) and booleans can be map keys
+    pub fn is_hashable(&self) -> bool {
         match self {
-            Value::ValObject(_object) => true,
+            Value::ValBool(_) => true,
+            Value::ValNumber(num) => !num.is_nan(),
+            Value::ValObject(Object::ObjString(_)) => true,
             _ => false,
         }
     }
@@ -204,24 +247,18 @@ impl Value {
     }
 
 
-    pub fn print_value(&self) {
+    // the value as text, without the quotes read_value_str puts around it
+    pub fn to_display_string(&self) -> String {
         match self {
-            Value::ValBool(boolean) => print!("'{}'", boolean),
-            Value::ValVoid(()) => print!("'nil'"),
-            Value::ValNumber(val) => print!("'{}'", val),
-            Value::ValObject(object) => print!("'{}'", object.get_object_data().unwrap_or("")),
-            //_ => panic!("Value not recognised, cannot print"),
+            Value::ValBool(boolean) => boolean.to_string(),
+            Value::ValVoid(()) => String::from("nil"),
+            Value::ValNumber(val) => val.to_string(),
+            Value::ValObject(object) => object.to_display_string(),
         }
     }
 
     pub fn read_value_str(&self) -> String {
-        match self {
-            Value::ValBool(boolean) => format!("'{}'", boolean),
-            Value::ValVoid(()) => String::from("'nil'"),
-            Value::ValNumber(val) => format!("'{}'", val),
-            Value::ValObject(object) => format!("'{}'", object.get_object_data().unwrap_or("")),
-            //_ => panic!("Value not recognised, cannot print"),
-        }
+        format!("'{}'", self.to_display_string())
     }
 
    // pub fn read_value(&self, which: usize)
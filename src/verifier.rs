@@ -0,0 +1,278 @@
+/*
+    Checks bytecode before the VM runs it.
+
+    The VM trusts the code it executes, it indexes constants, locals and the stack without
+    bounds checks. Code from the compiler holds up, but a loaded or hand-built chunk could
+    make it panic or read the wrong values, so such code is verified first.
+
+    Every instruction is decoded once to check the opcodes, that the operands fit in the code,
+    the constants they name exist and have the right type, and the global slots exist.
+    Then the reachable instructions are walked along every branch, tracking the stack depth of
+    the frame, to check jumps land on an instruction, no instruction pops more than the frame
+    has, locals are in range, paths that meet agree on the depth, and no path runs off the end.
+*/
+use crate::chunk::{Chunk, OpCode};
+use crate::object::{Object, ObjFunction};
+use crate::value::Value;
+
+// how deep functions can be declared inside each other, loading and verifying recurse into every level
+pub const MAX_NESTING: usize = 256;
+
+/*
+    Verify a top level script and every function declared in it.
+    The VM runs the script without arguments or captured variables, so it can not declare any.
+*/
+pub fn verify(script: &ObjFunction, global_count: usize) -> Result<(), String> {
+    if script.arity != 0 || script.upvalue_count != 0 {
+        return Err(in_function(script, String::from("a script can not take parameters or capture variables.")));
+    }
+    verify_function(script, global_count, 0)
+}
+
+// verify the function and every function declared in it, depth is how many functions it is declared in
+fn verify_function(function: &ObjFunction, global_count: usize, depth: usize) -> Result<(), String> {
+    let instructions = decode(function, global_count)
+        .map_err(|message| in_function(function, message))?;
+    check_stack(function, &instructions)
+        .map_err(|message| in_function(function, message))?;
+
+    for constant in &function.chunk.constants {
+        if let Value::ValObject(Object::ObjFunction(nested)) = constant {
+            if depth == MAX_NESTING {
+                return Err(String::from("Functions are nested too deeply."));
+            }
+            verify_function(nested, global_count, depth + 1)?;
+        }
+    }
+    Ok(())
+}
+
+fn in_function(function: &ObjFunction, message: String) -> String {
+    format!("Invalid bytecode in {}: {}", function.display_name(), message)
+}
+
+// an instruction with its operands read
+#[derive(Debug)]
+struct Instruction {
+    code: OpCode,
+    operand: usize, // constant index, slot or count, 0 for instructions without one
+    arg_count: usize, // second operand of the invoke instructions
+    jump: Option<usize>, // offset a jump, loop or for-in iteration can continue at
+    upvalues: Vec<(bool, usize)>, // captures of a closure, is_local and index
+    next: usize, // offset of the following instruction
+}
+
+/*
+    Decode every instruction, keyed by its offset.
+    None marks offsets that are in the middle of an instruction.
+*/
+fn decode(function: &ObjFunction, global_count: usize) -> Result<Vec<Option<Instruction>>, String> {
+    let chunk = &function.chunk;
+    let mut instructions: Vec<Option<Instruction>> = (0..chunk.code.len()).map(|_| None).collect();
+
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let instruction = decode_instruction(chunk, offset)?;
+        check_operand(function, &instruction, offset, global_count)?;
+
+        let next = instruction.next;
+        instructions[offset] = Some(instruction);
+        offset = next;
+    }
+
+    for (offset, instruction) in instructions.iter().enumerate() {
+        if let Some(target) = instruction.as_ref().and_then(|instruction| instruction.jump) {
+            if target >= instructions.len() || instructions[target].is_none() {
+                return Err(format!("jump at offset {} does not land on an instruction.", offset));
+            }
+        }
+    }
+
+    Ok(instructions)
+}
+
+fn decode_instruction(chunk: &Chunk, offset: usize) -> Result<Instruction, String> {
+    let code = OpCode::from_byte(chunk.code[offset])
+        .ok_or_else(|| format!("unknown opcode {} at offset {}.", chunk.code[offset], offset))?;
+
+    let mut next = offset + 1 + code.operand_len();
+    let operands = chunk.code.get(offset + 1..next)
+        .ok_or_else(|| format!("operands of the instruction at offset {} are cut off.", offset))?;
+
+    let mut instruction = Instruction { code, operand: 0, arg_count: 0, jump: None, upvalues: Vec::new(), next };
+    let short = |high: u8, low: u8| (usize::from(high) << 8) | usize::from(low);
+
+    match code {
+        OpCode::OpJump | OpCode::OpJumpIfFalse => instruction.jump = Some(next + short(operands[0], operands[1])),
+        OpCode::OpLoop => {
+            let target = next.checked_sub(short(operands[0], operands[1]))
+                .ok_or_else(|| format!("loop at offset {} jumps before the start of the code.", offset))?;
+            instruction.jump = Some(target);
+        },
+        OpCode::OpForIter => {
+            instruction.operand = usize::from(operands[0]);
+            instruction.jump = Some(next + short(operands[1], operands[2]));
+        },
+        OpCode::OpInvoke | OpCode::OpSuperInvoke | OpCode::OpInvokeLong | OpCode::OpSuperInvokeLong => {
+            let (index, count) = operands.split_at(operands.len() - 1);
+            instruction.operand = read_operand(index);
+            instruction.arg_count = usize::from(count[0]);
+        },
+        _ => instruction.operand = read_operand(operands),
+    }
+
+    // the upvalue pairs come after the closure operand
+    if matches!(code, OpCode::OpClosure | OpCode::OpClosureLong) {
+        let upvalue_count = match chunk.constants.get(instruction.operand) {
+            Some(Value::ValObject(Object::ObjFunction(function))) => function.upvalue_count,
+            _ => return Err(format!("closure at offset {} does not refer to a function constant.", offset)),
+        };
+
+        for _ in 0..upvalue_count {
+            let pair = chunk.code.get(next..next + 2)
+                .ok_or_else(|| format!("upvalues of the closure at offset {} are cut off.", offset))?;
+            let is_local = match pair[0] {
+                0 => false,
+                1 => true,
+                flag => return Err(format!("invalid upvalue flag {} in the closure at offset {}.", flag, offset)),
+            };
+            instruction.upvalues.push((is_local, usize::from(pair[1])));
+            next += 2;
+        }
+        instruction.next = next;
+    }
+
+    Ok(instruction)
+}
+
+// a one or three byte operand, high byte first
+fn read_operand(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |operand, byte| (operand << 8) | usize::from(*byte))
+}
+
+// check the operands that do not depend on the stack
+fn check_operand(function: &ObjFunction, instruction: &Instruction, offset: usize, global_count: usize) -> Result<(), String> {
+    let constants = &function.chunk.constants;
+    let constant = || constants.get(instruction.operand)
+        .ok_or_else(|| format!("constant {} at offset {} does not exist.", instruction.operand, offset));
+
+    let problem = match instruction.code {
+        OpCode::OpConstant | OpCode::OpConstantLong => constant().err(),
+        OpCode::OpClass | OpCode::OpClassLong | OpCode::OpGetProperty | OpCode::OpGetPropertyLong
+        | OpCode::OpSetProperty | OpCode::OpSetPropertyLong | OpCode::OpMethod | OpCode::OpMethodLong
+        | OpCode::OpInvoke | OpCode::OpInvokeLong | OpCode::OpGetSuper | OpCode::OpGetSuperLong
+        | OpCode::OpSuperInvoke | OpCode::OpSuperInvokeLong => match constant() {
+            Ok(Value::ValObject(Object::ObjString(_))) => None,
+            Ok(_) => Some(format!("name constant at offset {} is not a string.", offset)),
+            Err(message) => Some(message),
+        },
+        OpCode::OpGetUpvalue | OpCode::OpSetUpvalue if instruction.operand >= function.upvalue_count => {
+            Some(format!("upvalue {} at offset {} does not exist.", instruction.operand, offset))
+        },
+        OpCode::OpRange if instruction.operand > 1 => {
+            Some(format!("invalid range flag {} at offset {}.", instruction.operand, offset))
+        },
+        code if code.is_global() && instruction.operand >= global_count => {
+            Some(format!("global slot {} at offset {} does not exist.", instruction.operand, offset))
+        },
+        _ => None,
+    };
+
+    if let Some(problem) = problem {
+        return Err(problem);
+    }
+
+    // a closure can only capture upvalues of the function it is created in
+    for (is_local, index) in &instruction.upvalues {
+        if !is_local && *index >= function.upvalue_count {
+            return Err(format!("closure at offset {} captures upvalue {} which does not exist.", offset, index));
+        }
+    }
+
+    Ok(())
+}
+
+// values the instruction pops and pushes, instructions that only peek pop and push the value again
+fn stack_effect(instruction: &Instruction) -> (usize, usize) {
+    let count = instruction.operand;
+    match instruction.code {
+        OpCode::OpConstant | OpCode::OpConstantLong | OpCode::OpVoid | OpCode::OpTrue | OpCode::OpFalse
+        | OpCode::OpGetGlobal | OpCode::OpGetGlobalLong | OpCode::OpGetLocal | OpCode::OpGetUpvalue
+        | OpCode::OpClosure | OpCode::OpClosureLong | OpCode::OpClass | OpCode::OpClassLong => (0, 1),
+        OpCode::OpNegate | OpCode::OpNot | OpCode::OpToString | OpCode::OpSetGlobal | OpCode::OpSetGlobalLong
+        | OpCode::OpSetLocal | OpCode::OpSetUpvalue | OpCode::OpJumpIfFalse | OpCode::OpGetProperty
+        | OpCode::OpGetPropertyLong => (1, 1),
+        OpCode::OpAdd | OpCode::OpSubtract | OpCode::OpMultiply | OpCode::OpDivide | OpCode::OpEqual
+        | OpCode::OpGreater | OpCode::OpLess | OpCode::OpSetProperty | OpCode::OpSetPropertyLong
+        | OpCode::OpMethod | OpCode::OpMethodLong | OpCode::OpInherit | OpCode::OpGetSuper | OpCode::OpGetSuperLong
+        | OpCode::OpGetIndex | OpCode::OpRange => (2, 1),
+        OpCode::OpSetIndex => (3, 1),
+        OpCode::OpReturn | OpCode::OpEmit | OpCode::OpPop | OpCode::OpCloseUpvalue | OpCode::OpDefineGlobal
+        | OpCode::OpDefineConstGlobal | OpCode::OpDefineGlobalLong | OpCode::OpDefineConstGlobalLong => (1, 0),
+        OpCode::OpPopN => (count, 0),
+        OpCode::OpJump | OpCode::OpLoop | OpCode::OpForIter => (0, 0),
+        OpCode::OpCall => (count + 1, 1),
+        OpCode::OpInvoke | OpCode::OpInvokeLong => (instruction.arg_count + 1, 1),
+        // the superclass is on top of the receiver and the arguments
+        OpCode::OpSuperInvoke | OpCode::OpSuperInvokeLong => (instruction.arg_count + 2, 1),
+        OpCode::OpBuildList => (count, 1),
+        OpCode::OpBuildMap => (2 * count, 1),
+    }
+}
+
+/*
+    Walk every path through the code from the start, recording the stack depth each instruction
+    is reached with. Depths count the slots of the frame, slot zero holds the function and the
+    parameters come after it. Slot zero is never popped.
+*/
+fn check_stack(function: &ObjFunction, instructions: &[Option<Instruction>]) -> Result<(), String> {
+    let mut depths: Vec<Option<usize>> = vec![None; instructions.len()];
+    let mut pending = vec![(0, function.arity + 1)];
+
+    while let Some((offset, depth)) = pending.pop() {
+        let instruction = instructions.get(offset).and_then(|instruction| instruction.as_ref())
+            .ok_or_else(|| String::from("execution can run past the end of the code."))?;
+
+        match depths[offset] {
+            Some(known) if known == depth => continue,
+            Some(known) => {
+                return Err(format!("instruction at offset {} is reached with stack depths {} and {}.", offset, known, depth));
+            },
+            None => depths[offset] = Some(depth),
+        }
+
+        let (pops, pushes) = stack_effect(instruction);
+        if pops >= depth {
+            return Err(format!("instruction at offset {} pops more values than the frame has.", offset));
+        }
+
+        let slot_in_range = match instruction.code {
+            OpCode::OpGetLocal | OpCode::OpSetLocal => instruction.operand < depth,
+            // the iterated value and the position are two locals
+            OpCode::OpForIter => instruction.operand + 1 < depth,
+            _ => instruction.upvalues.iter().all(|(is_local, index)| !is_local || *index < depth),
+        };
+        if !slot_in_range {
+            return Err(format!("instruction at offset {} refers to a local that does not exist.", offset));
+        }
+
+        let after = depth - pops + pushes;
+        match instruction.code {
+            OpCode::OpReturn => {},
+            OpCode::OpJump | OpCode::OpLoop => pending.push((instruction.jump.unwrap_or(offset), after)),
+            // the element is only pushed when the loop goes on
+            OpCode::OpForIter => {
+                pending.push((instruction.jump.unwrap_or(offset), after));
+                pending.push((instruction.next, after + 1));
+            },
+            _ => {
+                if let Some(target) = instruction.jump {
+                    pending.push((target, after));
+                }
+                pending.push((instruction.next, after));
+            },
+        }
+    }
+
+    Ok(())
+}
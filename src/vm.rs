@@ -1,51 +1,160 @@
-use core::panic;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
 
+use crate::bytecode;
 use crate::debug;
+use crate::verifier;
 use crate::chunk::{Chunk, OpCode};
-use crate::object::{Object, ObjString};
+use crate::object::{Object, ObjClosure, ObjUpvalue, ObjClass, ObjInstance, ObjBoundMethod, ObjList, ObjMap, ObjRange, ObjNative, ObjFunction, NativeFn, Interner};
 use crate::value::Value;
 use crate::compiler::Compiler;
+use crate::diagnostic::{self, Diagnostic};
+use crate::globals::GlobalNames;
+use crate::span::{self, Span};
 
-use log::debug as log_debug;
 use log::{trace};
 //use crate::compiler::Compiler;
 
+// maximum depth of nested calls before the VM reports a stack overflow
+const FRAMES_MAX: usize = 64;
+
 #[derive(Debug)]
 pub enum InterpretResult {
-    Ok,
-    CompileError,
-    RuntimeError,
+    Ok(Value), // the value the script returned, void unless it ends in an expression statement
+    CompileError(Vec<Diagnostic>),
+    RuntimeError(RuntimeError),
+}
+
+/*
+    Error raised while a script is running.
+
+    span is the token the failing instruction was compiled from and trace holds every call that
+    was active at the time, innermost first, so the first entry is on the same line.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub span: Span,
+    pub trace: Vec<TraceFrame>,
+}
+
+// one active call in the trace of a runtime error. function is None for the top level script
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    pub function: Option<String>,
+    pub line: i32,
+}
+
+impl RuntimeError {
+    // file:line:col and the message, the source line with the token underlined if the source is given, then the trace
+    pub fn render(&self, file: &str, source: Option<&str>) -> String {
+        let mut rendered = format!("{}:{}:{}: Error: {}", file, self.span.line, self.span.column, self.message);
+        if let Some(snippet) = source.and_then(|source| span::snippet(source, self.span)) {
+            rendered.push('\n');
+            rendered.push_str(&snippet);
+        }
+        for frame in &self.trace {
+            rendered.push('\n');
+            rendered.push_str(&frame.to_string());
+        }
+        rendered
+    }
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {}()", self.line, name),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Error: {}", self.message)?;
+        for frame in &self.trace {
+            write!(f, "\n{}", frame)?;
+        }
+        Ok(())
+    }
 }
 
+impl std::error::Error for RuntimeError {}
+
 //to produce formated runtime error messages
 // takes string and a variable number of arguments.
+// evaluates to a RuntimeError with the line of every active call, and resets the stack so the VM can be used again
 macro_rules! run_time_error {
-    ($chunk: expr, $ip: expr, $format: expr $(, $($arg:expr), *)?) => {
+    ($vm: expr, $format: expr $(, $($arg:expr), *)?) => {
         {
-            eprintln!($format $(, $($arg), *)?);
-            let line = $chunk.lines[$ip - 1];
-            eprintln!("[line {}] in script", line);
-            //self.reset_stack();
+            let error = $vm.runtime_error(format!($format $(, $($arg), *)?));
+            $vm.reset_stack();
+            error
         }
     };
 }
 
+/*
+    A single ongoing function call.
+
+    ip is only saved here when the function calls another one,
+    while a function is running the VM's own ip is the live one.
+    slot_base is the index of the first stack slot that belongs to this call.
+*/
+#[derive(Debug)]
+struct CallFrame {
+    closure: Rc<ObjClosure>,
+    ip: usize,
+    slot_base: usize,
+}
+
+// value stored in a global slot. value is None until the definition of the global has run
+#[derive(Debug, Clone, Default)]
+struct Global {
+    value: Option<Value>,
+    is_const: bool, // defined with const, can not be assigned or redefined
+}
+
+// writer the VM sends text to, chosen by the host
+struct Sink(Box<dyn Write>);
+
+impl fmt::Debug for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Sink")
+    }
+}
+
 /*
     Struct to execute the bytecode instructions
 */
 #[derive(Debug)]
 pub struct VM{
-    //chunk: Chunk,
-    ip: usize, //indexes into the next instruction in the chunk
+    ip: usize, //indexes into the next instruction in the chunk of the current frame
     stack: Vec<Value>,
-    globals: HashMap<String, Value>
+    globals: Vec<Global>, // indexed by the slots in global_names
+    global_names: GlobalNames,
+    frames: Vec<CallFrame>,
+    open_upvalues: Vec<Rc<RefCell<ObjUpvalue>>>, // upvalues still pointing into the stack, sorted by slot
+    strings: Interner, // every string the compiler and the VM create is interned here
+    output: Sink, // where emit writes, stdout unless the host changes it
+    diagnostics: Sink, // compile and runtime errors are written here as well as returned, discarded by default
+    source_name: String, // file name the diagnostics refer to the source by
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        VM::new()
+    }
 }
 
-#[cfg_attr(feature = "trace", trace)]
 impl VM {
     pub fn new() -> VM{
-        VM {ip: 0, stack: Vec::new(), globals: HashMap::new()}
+        VM {ip: 0, stack: Vec::new(), globals: Vec::new(), global_names: GlobalNames::new(), frames: Vec::new(), open_upvalues: Vec::new(), strings: Interner::new(),
+            output: Sink(Box::new(io::stdout())), diagnostics: Sink(Box::new(io::sink())),
+            source_name: String::from("<script>")}
     }
 
     // read the next 2 bytes from the chunk, combine them into a single usize value
@@ -53,38 +162,46 @@ impl VM {
     fn read_short(&mut self, chunk: &Chunk) -> usize {
         self.ip += 2;
 
-        let result = usize::from(chunk.code[self.ip - 2] as u8) << 8 | usize::from(chunk.code[self.ip - 1] as u8);
-
-        result
+        (usize::from(chunk.code[self.ip - 2]) << 8) | usize::from(chunk.code[self.ip - 1])
     }
 
-    //returns the next instruction to which ip points to
-    fn read_byte(&mut self, chunk: &Chunk) -> OpCode{
-        let instruction: OpCode = chunk.code[self.ip].into();
+    //returns the next byte to which ip points to. it can be an instruction or an operand
+    fn read_byte(&mut self, chunk: &Chunk) -> u8 {
+        let byte = chunk.code[self.ip];
         self.ip += 1;
-        instruction
-        
+        byte
+    }
+
+    /*
+        Instructions taking a constant index or a global slot come in a short and a long form.
+        Read the operand of the matching size, the long one is three bytes, high byte first.
+    */
+    fn read_operand(&mut self, chunk: &Chunk, instruction: OpCode) -> usize {
+        if !instruction.is_long() {
+            return self.read_byte(chunk) as usize;
+        }
+
+        self.ip += 3;
+        (usize::from(chunk.code[self.ip - 3]) << 16)
+            | (usize::from(chunk.code[self.ip - 2]) << 8)
+            | usize::from(chunk.code[self.ip - 1])
     }
 
     // given chunk, read the constant_index at current ip and look up value in chunk's constants
     // returns a reference to the value
-    fn read_constant<'a>(&'a mut self, chunk: &'a Chunk) -> &Value {
-        let index: usize = chunk.code[self.ip] as usize;
-        self.ip += 1;
-       // chunk.constants[index]
-       chunk.get_constant(index)
+    fn read_constant<'a>(&mut self, chunk: &'a Chunk, instruction: OpCode) -> &'a Value {
+        let index = self.read_operand(chunk, instruction);
+        chunk.get_constant(index)
     }
 
-
-    /*  
-        Read the constant index at current instruction, look up the value in chunk's constants. it will be a string object. 
+    /*
+        Read the constant index at current instruction, look up the value in chunk's constants. it will be a string object.
         return the string value of the object
     */
-    fn read_string(&mut self, chunk: &Chunk) -> String {
-        let string_object = self.read_constant(chunk);
-        match string_object.get_inner_string() {
-            Some(inner_string) => inner_string.to_string(),
-            None => panic!("Empy string as identifier"),
+    fn read_string(&mut self, chunk: &Chunk, instruction: OpCode) -> Result<String, RuntimeError> {
+        match self.read_constant(chunk, instruction).get_inner_string() {
+            Some(inner_string) => Ok(inner_string.to_string()),
+            None => Err(run_time_error!(self, "{}", "Identifier constant is not a string.")),
         }
     }
 
@@ -92,13 +209,11 @@ impl VM {
         self.stack.push(value);
     }
 
-    fn pop(&mut self) -> Value {
+    fn pop(&mut self) -> Result<Value, RuntimeError> {
         let value: Option<Value> = self.stack.pop();
         match value {
-            Some(value) => {
-                return value;
-            },
-            None => panic!("VM stack is empty"),
+            Some(value) => Ok(value),
+            None => Err(run_time_error!(self, "{}", "VM stack is empty.")),
         }
     }
 
@@ -107,19 +222,375 @@ impl VM {
         &self.stack[self.stack.len() - depth - 1]
     }
 
+    fn reset_stack(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+    }
+
+    // stack slot where the locals of the current call start
+    fn slot_base(&self) -> usize {
+        self.frames.last().unwrap().slot_base
+    }
+
+    // error with the given message, traced through every active call
+    fn runtime_error(&self, message: String) -> RuntimeError {
+        let mut trace = Vec::new();
+        for (depth, frame) in self.frames.iter().enumerate().rev() {
+            // the innermost frame has not saved its ip yet
+            let ip = match depth == self.frames.len() - 1 {
+                true => self.ip,
+                false => frame.ip,
+            };
+            let function = &frame.closure.function;
+            let line = function.chunk.get_line(ip.saturating_sub(1));
+            let name = function.name.as_ref().map(|name| name.as_str().to_string());
+            trace.push(TraceFrame { function: name, line });
+        }
+
+        let span = match self.frames.last() {
+            Some(frame) => frame.closure.function.chunk.get_span(self.ip.saturating_sub(1)),
+            None => Span::default(),
+        };
+        RuntimeError { message, span, trace }
+    }
 
      #[cfg_attr(feature = "tracing", tracing::instrument)]
-    fn concatenate(&mut self) {
-        let op_r = self.pop();
-        let op_l = self.pop();
+    fn concatenate(&mut self) -> Result<(), RuntimeError> {
+        let op_r = self.pop()?;
+        let op_l = self.pop()?;
 
         match (op_r, op_l) {
             (Value::ValObject(object_right), Value::ValObject(object_left)) => {
                 let result = format!("{}{}", object_left.get_object_data().unwrap_or(""), object_right.get_object_data().unwrap_or(""));
-                self.push(Value::ValObject(Object::ObjString(ObjString::from_str(&result))));
+                let string = self.strings.intern(&result);
+                self.push(Value::ValObject(Object::ObjString(string)));
+                Ok(())
+            },
+            (_, _) => Err(run_time_error!(self, "{}", "Operands must be strings to concatenate")),
+        }
+    }
+
+    /*
+        Return the upvalue for the given stack slot.
+        Closures capturing the same variable must share one upvalue, so reuse an open one if it exists.
+    */
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<ObjUpvalue>> {
+        let position = self.open_upvalues.iter().position(|upvalue| match *upvalue.borrow() {
+            ObjUpvalue::Open(open_slot) => open_slot >= slot,
+            ObjUpvalue::Closed(_) => false,
+        });
+
+        if let Some(index) = position {
+            if let ObjUpvalue::Open(open_slot) = *self.open_upvalues[index].borrow() {
+                if open_slot == slot {
+                    return Rc::clone(&self.open_upvalues[index]);
+                }
+            }
+        }
+
+        let upvalue = Rc::new(RefCell::new(ObjUpvalue::Open(slot)));
+        let index = position.unwrap_or(self.open_upvalues.len());
+        self.open_upvalues.insert(index, Rc::clone(&upvalue));
+        upvalue
+    }
+
+    /*
+        Close every open upvalue that points at slot `last` or above.
+        The value is copied out of the stack so it survives the slot being popped.
+    */
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = match *upvalue.borrow() {
+                ObjUpvalue::Open(slot) => slot,
+                ObjUpvalue::Closed(_) => unreachable!("closed upvalue in open list"),
+            };
+
+            if slot < last {
+                break;
+            }
+
+            let value = self.stack[slot].clone();
+            *upvalue.borrow_mut() = ObjUpvalue::Closed(value);
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn read_upvalue(&self, upvalue: &RefCell<ObjUpvalue>) -> Value {
+        match &*upvalue.borrow() {
+            ObjUpvalue::Open(slot) => self.stack[*slot].clone(),
+            ObjUpvalue::Closed(value) => value.clone(),
+        }
+    }
+
+    fn write_upvalue(&mut self, upvalue: &RefCell<ObjUpvalue>, value: Value) {
+        let mut upvalue = upvalue.borrow_mut();
+        match &mut *upvalue {
+            ObjUpvalue::Open(slot) => self.stack[*slot] = value,
+            ObjUpvalue::Closed(closed) => *closed = value,
+        }
+    }
+
+    /*
+        Push a new call frame for the closure.
+        The closure and its arguments are already on top of the stack.
+    */
+    fn call(&mut self, closure: Rc<ObjClosure>, arg_count: usize) -> Result<(), RuntimeError> {
+        let arity = closure.function.arity;
+        if arg_count != arity {
+            return Err(run_time_error!(self, "Expected {} arguments but got {}.", arity, arg_count));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err(run_time_error!(self, "{}", "Stack overflow."));
+        }
+
+        if let Some(caller) = self.frames.last_mut() {
+            caller.ip = self.ip;
+        }
+
+        let slot_base = self.stack.len() - arg_count - 1;
+        self.frames.push(CallFrame { closure, ip: 0, slot_base });
+        self.ip = 0;
+        Ok(())
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), RuntimeError> {
+        match callee {
+            Value::ValObject(Object::ObjClosure(closure)) => self.call(closure, arg_count),
+            // natives run right away, their result replaces the callee and the arguments
+            Value::ValObject(Object::ObjNative(native)) => {
+                if arg_count != native.arity {
+                    return Err(run_time_error!(self, "Expected {} arguments but got {}.", native.arity, arg_count));
+                }
+
+                let args_start = self.stack.len() - arg_count;
+                match (native.function)(&self.stack[args_start..]) {
+                    Ok(result) => {
+                        let result = self.intern_value(result);
+                        self.stack.truncate(args_start - 1);
+                        self.push(result);
+                        Ok(())
+                    },
+                    Err(message) => Err(run_time_error!(self, "{}", message)),
+                }
+            },
+            // calling a class creates an instance and runs the initializer on it, if there is one
+            Value::ValObject(Object::ObjClass(class)) => {
+                let instance = Rc::new(RefCell::new(ObjInstance::new(Rc::clone(&class))));
+                let slot = self.stack.len() - arg_count - 1;
+                self.stack[slot] = Value::ValObject(Object::ObjInstance(instance));
+
+                let initializer = class.borrow().methods.get("init").cloned();
+                match initializer {
+                    Some(initializer) => self.call(initializer, arg_count),
+                    None if arg_count != 0 => Err(run_time_error!(self, "Expected 0 arguments but got {}.", arg_count)),
+                    None => Ok(()),
+                }
+            },
+            // the receiver takes the place of the callee so it ends up in slot zero as 'this'
+            Value::ValObject(Object::ObjBoundMethod(bound)) => {
+                let slot = self.stack.len() - arg_count - 1;
+                self.stack[slot] = bound.receiver.clone();
+                self.call(Rc::clone(&bound.method), arg_count)
             },
-            (_, _) => {
-                panic!("Operands must be strings to concatenate");
+            _ => Err(run_time_error!(self, "{}", "Can only call functions.")),
+        }
+    }
+
+    /*
+        Call a method on the receiver sitting below the arguments on the stack.
+
+        A field holding a function shadows a method with the same name,
+        otherwise the method is called directly without creating a bound method.
+    */
+    fn invoke(&mut self, name: &str, arg_count: usize) -> Result<(), RuntimeError> {
+        let instance = match self.peek(arg_count) {
+            Value::ValObject(Object::ObjInstance(instance)) => Rc::clone(instance),
+            Value::ValObject(Object::ObjList(list)) => {
+                let list = Rc::clone(list);
+                return self.invoke_list_method(&list, name, arg_count);
+            },
+            Value::ValObject(Object::ObjMap(map)) => {
+                let map = Rc::clone(map);
+                return self.invoke_map_method(&map, name, arg_count);
+            },
+            Value::ValObject(Object::ObjRange(range)) => {
+                let range = Rc::clone(range);
+                return self.invoke_range_method(&range, name, arg_count);
+            },
+            _ => {
+                return Err(run_time_error!(self, "{}", "Only instances have methods."));
+            }
+        };
+
+        let field = instance.borrow().fields.get(name).cloned();
+        if let Some(field) = field {
+            let slot = self.stack.len() - arg_count - 1;
+            self.stack[slot] = field.clone();
+            return self.call_value(field, arg_count);
+        }
+
+        let class = Rc::clone(&instance.borrow().class);
+        self.invoke_from_class(&class, name, arg_count)
+    }
+
+    fn invoke_from_class(&mut self, class: &RefCell<ObjClass>, name: &str, arg_count: usize) -> Result<(), RuntimeError> {
+        let method = class.borrow().methods.get(name).cloned();
+        match method {
+            Some(method) => self.call(method, arg_count),
+            None => {
+                Err(run_time_error!(self, "Undefined property '{}'.", name))
+            }
+        }
+    }
+
+    /*
+        Built in methods on lists.
+        The arguments and the list are replaced on the stack by the result.
+    */
+    fn invoke_list_method(&mut self, list: &RefCell<ObjList>, name: &str, arg_count: usize) -> Result<(), RuntimeError> {
+        let arity = match name {
+            "push" => 1,
+            "pop" | "len" => 0,
+            _ => {
+                return Err(run_time_error!(self, "Undefined list method '{}'.", name));
+            }
+        };
+
+        if arg_count != arity {
+            return Err(run_time_error!(self, "Expected {} arguments but got {}.", arity, arg_count));
+        }
+
+        let result = match name {
+            "push" => {
+                let value = self.pop()?;
+                list.borrow_mut().items.push(value);
+                Value::ValVoid(())
+            },
+            "pop" => match list.borrow_mut().items.pop() {
+                Some(value) => value,
+                None => {
+                    return Err(run_time_error!(self, "{}", "Cannot pop from an empty list."));
+                }
+            },
+            _ => Value::ValNumber(list.borrow().items.len() as f64),
+        };
+
+        // pop the list itself
+        self.pop()?;
+        self.push(result);
+        Ok(())
+    }
+
+    /*
+        Built in methods on ranges. 'step' returns a copy of the range counting by the given amount.
+        The argument and the range are replaced on the stack by the result.
+    */
+    fn invoke_range_method(&mut self, range: &ObjRange, name: &str, arg_count: usize) -> Result<(), RuntimeError> {
+        if name != "step" {
+            return Err(run_time_error!(self, "Undefined range method '{}'.", name));
+        }
+
+        if arg_count != 1 {
+            return Err(run_time_error!(self, "Expected {} arguments but got {}.", 1, arg_count));
+        }
+
+        let step = match self.pop()? {
+            Value::ValNumber(step) if step != 0.0 => step,
+            _ => {
+                return Err(run_time_error!(self, "{}", "Range step must be a non-zero number."));
+            }
+        };
+
+        // pop the range itself
+        self.pop()?;
+        let stepped = ObjRange { step, ..*range };
+        self.push(Value::ValObject(Object::ObjRange(Rc::new(stepped))));
+        Ok(())
+    }
+
+    /*
+        Built in methods on maps.
+        The arguments and the map are replaced on the stack by the result.
+    */
+    fn invoke_map_method(&mut self, map: &RefCell<ObjMap>, name: &str, arg_count: usize) -> Result<(), RuntimeError> {
+        let arity = match name {
+            "has" | "remove" => 1,
+            "keys" | "len" => 0,
+            _ => {
+                return Err(run_time_error!(self, "Undefined map method '{}'.", name));
+            }
+        };
+
+        if arg_count != arity {
+            return Err(run_time_error!(self, "Expected {} arguments but got {}.", arity, arg_count));
+        }
+
+        let result = match name {
+            "has" => {
+                let key = self.pop()?;
+                Value::ValBool(map.borrow().contains_key(&key))
+            },
+            // returns the removed value, or void if the key was not there
+            "remove" => {
+                let key = self.pop()?;
+                map.borrow_mut().remove(&key).unwrap_or(Value::ValVoid(()))
+            },
+            "keys" => {
+                let keys = map.borrow().keys().cloned().collect();
+                Value::ValObject(Object::ObjList(Rc::new(RefCell::new(ObjList::new(keys)))))
+            },
+            _ => Value::ValNumber(map.borrow().len() as f64),
+        };
+
+        // pop the map itself
+        self.pop()?;
+        self.push(result);
+        Ok(())
+    }
+
+    fn check_map_key(&mut self, key: &Value) -> Result<(), RuntimeError> {
+        if !key.is_hashable() {
+            return Err(run_time_error!(self, "{}", "Map key must be a string, number or boolean."));
+        }
+        Ok(())
+    }
+
+    /*
+        Check that the value can index a list of the given length.
+        Lists are indexed by whole numbers starting at zero.
+    */
+    fn list_index(&mut self, index: &Value, len: usize) -> Result<usize, RuntimeError> {
+        let number = match index {
+            Value::ValNumber(number) if number.fract() == 0.0 => *number,
+            _ => {
+                return Err(run_time_error!(self, "{}", "List index must be a whole number."));
+            }
+        };
+
+        if number < 0.0 || number >= len as f64 {
+            return Err(run_time_error!(self, "List index {} out of bounds for list of length {}.", number, len));
+        }
+
+        Ok(number as usize)
+    }
+
+    /*
+        Replace the instance on top of the stack with the named method bound to it.
+        Returns false if the class has no such method.
+    */
+    fn bind_method(&mut self, class: &RefCell<ObjClass>, name: &str) -> Result<(), RuntimeError> {
+        let method = class.borrow().methods.get(name).cloned();
+        match method {
+            Some(method) => {
+                let receiver = self.pop()?;
+                let bound = ObjBoundMethod { receiver, method };
+                self.push(Value::ValObject(Object::ObjBoundMethod(Rc::new(bound))));
+                Ok(())
+            },
+            None => {
+                Err(run_time_error!(self, "Undefined property '{}'.", name))
             }
         }
     }
@@ -128,62 +599,531 @@ impl VM {
         Entrypoint into the VM
     */
     pub fn interpret(&mut self, source: &str) -> InterpretResult {
-        let mut chunk: Chunk = Chunk::new();
-        let mut compiler = Compiler::new(&mut chunk, source);
-        
-        if !compiler.compile(){
-            return InterpretResult::CompileError;
+        match self.compile(source) {
+            Ok(function) => self.execute(function, Some(source)),
+            Err(errors) => InterpretResult::CompileError(errors),
         }
+    }
 
-       // compiler.compile(source);
+    // compile the source without running it. the diagnostics are also written to the diagnostics sink
+    pub fn compile(&mut self, source: &str) -> Result<ObjFunction, Vec<Diagnostic>> {
+        let (function, diagnostics) = self.check(source);
 
-        // ip = instruction pointer. Points to the next instruction to be executed
-        self.ip = 0;
-        let result: InterpretResult = self.run(&chunk);
-        result
-        
+        if !diagnostics.is_empty() {
+            let rendered = diagnostic::render_all(&diagnostics, &self.source_name, Some(source));
+            self.write_diagnostic(&rendered);
+        }
+        function.ok_or(diagnostics)
+    }
+
+    // compile the source and hand back every diagnostic, without writing them anywhere
+    pub fn check(&mut self, source: &str) -> (Option<ObjFunction>, Vec<Diagnostic>) {
+        let mut compiler = Compiler::new(source, &mut self.strings, &mut self.global_names);
+        let compiled = compiler.compile();
+
+        // the compiler may have handed out slots for new global names
+        self.globals.resize_with(self.global_names.len(), Global::default);
+        compiled
+    }
+
+    // serialize a script compiled by this VM, see bytecode
+    pub fn save(&self, function: &ObjFunction) -> Result<Vec<u8>, String> {
+        bytecode::write(function, &self.global_names)
+    }
+
+    // load a serialized script so it can be executed by this VM. it is verified first, it may not come from our compiler
+    pub fn load(&mut self, bytes: &[u8]) -> Result<ObjFunction, String> {
+        let function = bytecode::read(bytes, &mut self.strings, &self.global_names)
+            .and_then(|(function, new_globals)| {
+                verifier::verify(&function, self.global_names.len() + new_globals.len())?;
+                Ok((function, new_globals))
+            })
+            .map(|(function, new_globals)| {
+                // only a script that verified adds its globals, in the slots its code was given for them
+                for name in &new_globals {
+                    self.global_names.resolve(name);
+                }
+                function
+            });
+        self.globals.resize_with(self.global_names.len(), Global::default);
+
+        if let Err(message) = &function {
+            self.write_diagnostic(&format!("Error: {}", message));
+        }
+        function
+    }
+
+    // write the bytecode listing of a compiled script
+    pub fn disassemble(&self, function: &ObjFunction, out: &mut dyn Write) -> io::Result<()> {
+        debug::write_function(out, function, &self.global_names)
     }
 
     /*
-        Execute the bytecode instructions in a single chunk
+        Run a compiled or loaded script as the top level script.
+        source is what it was compiled from, if at hand, to show the failing line of a runtime error.
     */
-    fn run(&mut self, chunk: &Chunk) -> InterpretResult {
+    pub fn execute(&mut self, function: ObjFunction, source: Option<&str>) -> InterpretResult {
+        // the script closure sits in slot zero of the top level frame
+        let closure = Rc::new(ObjClosure::new(Rc::new(function)));
+        self.push(Value::ValObject(Object::ObjClosure(Rc::clone(&closure))));
+        match self.call(closure, 0).and_then(|_| self.run()) {
+            Ok(value) => InterpretResult::Ok(value),
+            Err(error) => {
+                let rendered = error.render(&self.source_name, source);
+                self.write_diagnostic(&rendered);
+                InterpretResult::RuntimeError(error)
+            },
+        }
+    }
+
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = Sink(output);
+    }
+
+    pub fn set_diagnostics(&mut self, diagnostics: Box<dyn Write>) {
+        self.diagnostics = Sink(diagnostics);
+    }
+
+    pub fn set_source_name(&mut self, name: &str) {
+        self.source_name = name.to_string();
+    }
+
+    // the error is returned to the host either way, a diagnostics sink that fails is ignored
+    fn write_diagnostic(&mut self, error: &dyn fmt::Display) {
+        let _ = writeln!(self.diagnostics.0, "{}", error);
+    }
+
+    // value of the global, None if no global of that name has been defined
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        let slot = self.global_names.slot(name)?;
+        self.globals[slot].value.clone()
+    }
+
+    // register a host function as a global, see set_global
+    pub fn define_native(&mut self, name: &str, arity: usize, function: Box<NativeFn>) -> Result<(), String> {
+        let native = Rc::new(ObjNative::new(name, arity, function));
+        self.set_global(name, Value::ValObject(Object::ObjNative(native)))
+    }
+
+    /*
+        Define the global, or replace its value if it already exists.
+        Constants can not be replaced, the error names the constant.
+    */
+    pub fn set_global(&mut self, name: &str, value: Value) -> Result<(), String> {
+        let slot = self.global_names.resolve(name);
+        self.globals.resize_with(self.global_names.len(), Global::default);
+
+        if self.globals[slot].is_const {
+            return Err(format!("Cannot assign to constant '{}'.", name));
+        }
+
+        let value = self.intern_value(value);
+        self.globals[slot].value = Some(value);
+        Ok(())
+    }
+
+    /*
+        Intern the strings in a value from the host, including the ones in lists and maps.
+        Strings are compared by identity, a string the VM did not intern would equal no other.
+    */
+    fn intern_value(&mut self, value: Value) -> Value {
+        match value {
+            Value::ValObject(Object::ObjString(string)) => Value::ValObject(Object::ObjString(self.strings.intern(string.as_str()))),
+            // the items are taken out while they are interned, so a list holding itself is only visited once
+            Value::ValObject(Object::ObjList(list)) => {
+                let items = std::mem::take(&mut list.borrow_mut().items);
+                let items = items.into_iter().map(|item| self.intern_value(item)).collect();
+                list.borrow_mut().items = items;
+                Value::ValObject(Object::ObjList(list))
+            },
+            // keys are hashed by content but compared by identity, so the map is filled again with the interned keys
+            Value::ValObject(Object::ObjMap(map)) => {
+                let entries = map.borrow_mut().take_entries();
+                for (key, value) in entries {
+                    let (key, value) = (self.intern_value(key), self.intern_value(value));
+                    map.borrow_mut().insert(key, value);
+                }
+                Value::ValObject(Object::ObjMap(map))
+            },
+            other => other,
+        }
+    }
+
+    /*
+        Execute the bytecode instructions of the function in the topmost call frame
+        until the top level script returns, and hand back the value it returned.
+    */
+    fn run(&mut self) -> Result<Value, RuntimeError> {
+        // comparisons can not fail once both operands are numbers
+        macro_rules! comparison_op {
+            ($op:tt) => {
+                {
+                    match (self.peek(0).is_number(), self.peek(1).is_number()) {
+                        (true, true) => {
+                            let right_operand: Value = self.pop()?;
+                            let left_operand: Value = self.pop()?;
+                            self.push(Value::from(left_operand $op right_operand));
+                        }
+                        (_, _) => {
+                            return Err(run_time_error!(self, "{}", "Operands must be numbers"));
+                        }
+                    }
+                }
+            }
+        }
+
         macro_rules! binary_op {
             ($op:tt) => {
                 {
                     let op_r = self.peek(0);
                     //println!("this works");
                     let op_l = self.peek(1);
-                    
+
                     match (op_r.is_number(), op_l.is_number()) {
                         (true, true) => {
-                            let right_operand: Value= self.pop();
-                            let left_operand: Value = self.pop();
-                            self.push(Value::from(left_operand $op right_operand));
+                            let right_operand: Value= self.pop()?;
+                            let left_operand: Value = self.pop()?;
+                            match left_operand $op right_operand {
+                                Ok(result) => self.push(result),
+                                Err(message) => return Err(run_time_error!(self, "{}", message)),
+                            }
                         }
                         (_, _) => {
-                            run_time_error!(chunk, self.ip, "Error: {}", "Operands must be numbers");
-                            return InterpretResult::RuntimeError;
+                            return Err(run_time_error!(self, "{}", "Operands must be numbers"));
                         }
                     }
                 }
             }
         }
-        
+
+        // closure whose chunk is being executed. changes on every call and return
+        let mut closure = Rc::clone(&self.frames.last().unwrap().closure);
+
         loop {
+            let chunk = &closure.function.chunk;
+
             // TODO: Put in verbose flag?
             trace!("stack:{:?}", self.stack);
 
-            debug::disassemble_instruction(chunk, self.ip as u8);
-            
-            // debug code ends  
+            if log::log_enabled!(log::Level::Debug) {
+                let mut listing = String::new();
+                if debug::disassemble_instruction(&mut listing, chunk, self.ip, &self.global_names).is_ok() {
+                    log::debug!("{}", listing.trim_end());
+                }
+            }
+
+            // debug code ends
 
-            let instruction: OpCode = self.read_byte(chunk);
+            let instruction: OpCode = self.read_byte(chunk).into();
 
             match instruction {
                 OpCode::OpReturn => {
+                    let result = self.pop()?;
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slot_base);
+
+                    if self.frames.is_empty() {
+                        // pop the script function itself
+                        self.pop()?;
+                        return Ok(result);
+                    }
 
-                    return InterpretResult::Ok;
+                    // discard the callee's arguments and locals, along with the callee itself
+                    self.stack.truncate(frame.slot_base);
+                    self.push(result);
+
+                    let caller = self.frames.last().unwrap();
+                    self.ip = caller.ip;
+                    closure = Rc::clone(&caller.closure);
+                },
+                OpCode::OpCall => {
+                    let arg_count = self.read_byte(chunk) as usize;
+                    let callee = self.peek(arg_count).clone();
+
+                    self.call_value(callee, arg_count)?;
+
+                    closure = Rc::clone(&self.frames.last().unwrap().closure);
+                },
+                // wrap the function constant in a closure, capturing the upvalues listed after it
+                OpCode::OpClosure | OpCode::OpClosureLong => {
+                    let function = match self.read_constant(chunk, instruction) {
+                        Value::ValObject(Object::ObjFunction(function)) => Rc::clone(function),
+                        _ => return Err(run_time_error!(self, "{}", "Closure operand is not a function.")),
+                    };
+
+                    let mut new_closure = ObjClosure::new(function);
+                    for _ in 0..new_closure.function.upvalue_count {
+                        let is_local = self.read_byte(chunk) == 1;
+                        let index = self.read_byte(chunk) as usize;
+
+                        let upvalue = match is_local {
+                            true => self.capture_upvalue(self.slot_base() + index),
+                            false => match closure.upvalues.get(index) {
+                                Some(upvalue) => Rc::clone(upvalue),
+                                None => return Err(run_time_error!(self, "Upvalue {} does not exist.", index)),
+                            },
+                        };
+                        new_closure.upvalues.push(upvalue);
+                    }
+
+                    self.push(Value::ValObject(Object::ObjClosure(Rc::new(new_closure))));
+                },
+                OpCode::OpClass | OpCode::OpClassLong => {
+                    let name = match self.read_constant(chunk, instruction) {
+                        Value::ValObject(Object::ObjString(name)) => name.clone(),
+                        _ => return Err(run_time_error!(self, "{}", "Class name is not a string.")),
+                    };
+                    let class = ObjClass::new(name);
+                    self.push(Value::ValObject(Object::ObjClass(Rc::new(RefCell::new(class)))));
+                },
+                // the method closure is on top of the stack with the class right below it
+                OpCode::OpMethod | OpCode::OpMethodLong => {
+                    let name = self.read_string(chunk, instruction)?;
+                    let method = match self.pop()? {
+                        Value::ValObject(Object::ObjClosure(method)) => method,
+                        _ => return Err(run_time_error!(self, "{}", "Method is not a closure.")),
+                    };
+
+                    match self.peek(0) {
+                        Value::ValObject(Object::ObjClass(class)) => {
+                            class.borrow_mut().methods.insert(name, method);
+                        },
+                        _ => return Err(run_time_error!(self, "{}", "Method defined outside of a class.")),
+                    }
+                },
+                // fields shadow methods, so look at the fields first
+                OpCode::OpGetProperty | OpCode::OpGetPropertyLong => {
+                    let name = self.read_string(chunk, instruction)?;
+                    let instance = match self.peek(0) {
+                        Value::ValObject(Object::ObjInstance(instance)) => Rc::clone(instance),
+                        _ => {
+                            return Err(run_time_error!(self, "{}", "Only instances have properties."));
+                        }
+                    };
+
+                    let field = instance.borrow().fields.get(&name).cloned();
+                    match field {
+                        Some(value) => {
+                            self.pop()?;
+                            self.push(value);
+                        },
+                        None => {
+                            let class = Rc::clone(&instance.borrow().class);
+                            self.bind_method(&class, &name)?;
+                        },
+                    }
+                },
+                // the value is on top of the stack with the instance right below it
+                OpCode::OpSetProperty | OpCode::OpSetPropertyLong => {
+                    let name = self.read_string(chunk, instruction)?;
+                    let instance = match self.peek(1) {
+                        Value::ValObject(Object::ObjInstance(instance)) => Rc::clone(instance),
+                        _ => {
+                            return Err(run_time_error!(self, "{}", "Only instances have fields."));
+                        }
+                    };
+
+                    let value = self.pop()?;
+                    instance.borrow_mut().fields.insert(name, value.clone());
+                    // the assignment expression evaluates to the assigned value
+                    self.pop()?;
+                    self.push(value);
+                },
+                OpCode::OpInvoke | OpCode::OpInvokeLong => {
+                    let name = self.read_string(chunk, instruction)?;
+                    let arg_count = self.read_byte(chunk) as usize;
+
+                    self.invoke(&name, arg_count)?;
+
+                    closure = Rc::clone(&self.frames.last().unwrap().closure);
+                },
+                // copy down inheritance: the superclass methods are copied into the subclass
+                // before the subclass defines its own, so overrides replace them
+                OpCode::OpInherit => {
+                    let superclass = match self.peek(1) {
+                        Value::ValObject(Object::ObjClass(superclass)) => Rc::clone(superclass),
+                        _ => {
+                            return Err(run_time_error!(self, "{}", "Superclass must be a class."));
+                        }
+                    };
+
+                    if let Value::ValObject(Object::ObjClass(subclass)) = self.peek(0) {
+                        let methods = superclass.borrow().methods.clone();
+                        subclass.borrow_mut().methods.extend(methods);
+                    }
+                    // pop the subclass, the superclass stays as the 'super' local
+                    self.pop()?;
+                },
+                OpCode::OpGetSuper | OpCode::OpGetSuperLong => {
+                    let name = self.read_string(chunk, instruction)?;
+                    let superclass = match self.pop()? {
+                        Value::ValObject(Object::ObjClass(superclass)) => superclass,
+                        _ => return Err(run_time_error!(self, "{}", "Super is not a class.")),
+                    };
+
+                    self.bind_method(&superclass, &name)?;
+                },
+                OpCode::OpSuperInvoke | OpCode::OpSuperInvokeLong => {
+                    let name = self.read_string(chunk, instruction)?;
+                    let arg_count = self.read_byte(chunk) as usize;
+                    let superclass = match self.pop()? {
+                        Value::ValObject(Object::ObjClass(superclass)) => superclass,
+                        _ => return Err(run_time_error!(self, "{}", "Super is not a class.")),
+                    };
+
+                    self.invoke_from_class(&superclass, &name, arg_count)?;
+
+                    closure = Rc::clone(&self.frames.last().unwrap().closure);
+                },
+                // the bounds are on the stack, start below end
+                OpCode::OpRange => {
+                    let inclusive = self.read_byte(chunk) == 1;
+                    let range = match (self.peek(1), self.peek(0)) {
+                        (Value::ValNumber(start), Value::ValNumber(end)) => ObjRange::new(*start, *end, inclusive),
+                        (_, _) => {
+                            return Err(run_time_error!(self, "{}", "Range bounds must be numbers."));
+                        }
+                    };
+
+                    self.pop()?;
+                    self.pop()?;
+                    self.push(Value::ValObject(Object::ObjRange(Rc::new(range))));
+                },
+                /*
+                    One step of a 'for in' loop. The slot after the iterated value holds the position reached in it.
+                    Push the next element and move the position past it, or jump out of the loop when there is none.
+                    Strings are walked one character at a time, maps by their keys in insertion order.
+                */
+                OpCode::OpForIter => {
+                    let slot = self.slot_base() + self.read_byte(chunk) as usize;
+                    let exit = self.read_short(chunk);
+                    let iterable = self.stack[slot].clone();
+                    let position = match self.stack[slot + 1] {
+                        Value::ValNumber(position) => position as usize,
+                        _ => return Err(run_time_error!(self, "{}", "Loop position is not a number.")),
+                    };
+
+                    let next = match &iterable {
+                        Value::ValObject(Object::ObjList(list)) => list.borrow().items.get(position)
+                            .map(|item| (item.clone(), position + 1)),
+                        Value::ValObject(Object::ObjMap(map)) => map.borrow().key_at(position)
+                            .map(|key| (key.clone(), position + 1)),
+                        Value::ValObject(Object::ObjRange(range)) => range.get(position)
+                            .map(|number| (Value::ValNumber(number), position + 1)),
+                        // the position is a byte offset, only bytecode that was not compiled by us can put it inside a character
+                        Value::ValObject(Object::ObjString(string)) => {
+                            let rest = match string.as_str().get(position..) {
+                                Some(rest) => rest,
+                                None => return Err(run_time_error!(self, "{}", "Loop position is not at a character of the string.")),
+                            };
+                            rest.chars().next().map(|character| {
+                                let character_string = self.strings.intern(character.encode_utf8(&mut [0; 4]));
+                                (Value::ValObject(Object::ObjString(character_string)), position + character.len_utf8())
+                            })
+                        },
+                        _ => {
+                            return Err(run_time_error!(self, "{}", "Can only iterate over strings, lists, maps and ranges."));
+                        }
+                    };
+
+                    match next {
+                        Some((element, next_position)) => {
+                            self.stack[slot + 1] = Value::ValNumber(next_position as f64);
+                            self.push(element);
+                        },
+                        None => self.ip += exit,
+                    }
+                },
+                // the elements are the top count values on the stack, first element deepest
+                OpCode::OpBuildList => {
+                    let count = self.read_byte(chunk) as usize;
+                    let items = self.stack.split_off(self.stack.len() - count);
+                    let list = ObjList::new(items);
+                    self.push(Value::ValObject(Object::ObjList(Rc::new(RefCell::new(list)))));
+                },
+                // keys and values alternate on the stack, first key deepest
+                OpCode::OpBuildMap => {
+                    let count = self.read_byte(chunk) as usize;
+                    let entries = self.stack.split_off(self.stack.len() - count * 2);
+
+                    let mut map = ObjMap::new();
+                    for pair in entries.chunks(2) {
+                        self.check_map_key(&pair[0])?;
+                        map.insert(pair[0].clone(), pair[1].clone());
+                    }
+
+                    self.push(Value::ValObject(Object::ObjMap(Rc::new(RefCell::new(map)))));
+                },
+                OpCode::OpGetIndex => {
+                    let index = self.pop()?;
+                    let target = self.pop()?;
+
+                    let value = match &target {
+                        Value::ValObject(Object::ObjMap(map)) => {
+                            let value = map.borrow().get(&index).cloned();
+                            match value {
+                                Some(value) => value,
+                                None => {
+                                    return Err(run_time_error!(self, "Undefined key {}.", index.read_value_str()));
+                                }
+                            }
+                        },
+                        Value::ValObject(Object::ObjList(list)) => {
+                            let len = list.borrow().items.len();
+                            let position = self.list_index(&index, len)?;
+                            list.borrow().items[position].clone()
+                        },
+                        _ => {
+                            return Err(run_time_error!(self, "{}", "Only lists and maps can be indexed."));
+                        }
+                    };
+
+                    self.push(value);
+                },
+                OpCode::OpSetIndex => {
+                    let value = self.pop()?;
+                    let index = self.pop()?;
+                    let target = self.pop()?;
+
+                    match &target {
+                        Value::ValObject(Object::ObjList(list)) => {
+                            let len = list.borrow().items.len();
+                            let position = self.list_index(&index, len)?;
+                            list.borrow_mut().items[position] = value.clone();
+                        },
+                        // assigning to a missing key adds it
+                        Value::ValObject(Object::ObjMap(map)) => {
+                            self.check_map_key(&index)?;
+                            map.borrow_mut().insert(index, value.clone());
+                        },
+                        _ => {
+                            return Err(run_time_error!(self, "{}", "Only lists and maps can be indexed."));
+                        }
+                    }
+
+                    // the assignment expression evaluates to the assigned value
+                    self.push(value);
+                },
+                OpCode::OpGetUpvalue => {
+                    let index = self.read_byte(chunk) as usize;
+                    let value = match closure.upvalues.get(index) {
+                        Some(upvalue) => self.read_upvalue(upvalue),
+                        None => return Err(run_time_error!(self, "Upvalue {} does not exist.", index)),
+                    };
+                    self.push(value);
+                },
+                OpCode::OpSetUpvalue => {
+                    let index = self.read_byte(chunk) as usize;
+                    let value = self.peek(0).clone();
+                    match closure.upvalues.get(index) {
+                        Some(upvalue) => self.write_upvalue(upvalue, value),
+                        None => return Err(run_time_error!(self, "Upvalue {} does not exist.", index)),
+                    }
+                },
+                // the local at the top of the stack is going out of scope but a closure captured it
+                OpCode::OpCloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop()?;
                 },
                 OpCode::OpJumpIfFalse => {
                     let offset = self.read_short(chunk);
@@ -202,14 +1142,16 @@ impl VM {
                     self.ip -= offset;
                 },
                 OpCode::OpEmit => {
-                    (self.pop()).print_value();
-                    println!();
+                    let value = self.pop()?;
+                    if let Err(err) = writeln!(self.output.0, "{}", value.read_value_str()) {
+                        return Err(run_time_error!(self, "Could not write output: {}", err));
+                    }
                 },
 
-                OpCode::OpConstant => {
-                    let constant = self.read_constant(chunk).clone();
+                OpCode::OpConstant | OpCode::OpConstantLong => {
+                    let constant = self.read_constant(chunk, instruction).clone();
                     self.push(constant);
-                    //println!("{}", constant);  
+                    //println!("{}", constant);
                 },
 
                 OpCode::OpAdd => {
@@ -217,14 +1159,21 @@ impl VM {
                     let op_l = self.peek(1);
 
                     match (op_r, op_l) {
-                        (Value::ValObject(Object::ObjString(_)), Value::ValObject(Object::ObjString(_))) => self.concatenate(),
+                        (Value::ValObject(Object::ObjString(_)), Value::ValObject(Object::ObjString(_))) => self.concatenate()?,
                         (Value::ValNumber(_), Value::ValNumber(_)) => binary_op!(+),
                         (_, _) => {
-                            run_time_error!(chunk, self.ip, "Error: {}", "Operands must be numbers or strings");
-                            return InterpretResult::RuntimeError;
+                            return Err(run_time_error!(self, "{}", "Operands must be numbers or strings"));
                         },
                     }
                 },
+                // strings are left alone, every other value is replaced by its text
+                OpCode::OpToString => {
+                    if !matches!(self.peek(0), Value::ValObject(Object::ObjString(_))) {
+                        let text = self.pop()?.to_display_string();
+                        let string = self.strings.intern(&text);
+                        self.push(Value::ValObject(Object::ObjString(string)));
+                    }
+                },
                 OpCode::OpSubtract => binary_op!(-),
                 OpCode::OpMultiply => binary_op!(*),
                 OpCode::OpDivide => binary_op!(/),
@@ -232,12 +1181,14 @@ impl VM {
                     let value = self.peek(0);
                     match value.is_bool() {
                         true => {
-                            let top_val = self.pop();
-                            self.push(!top_val);
+                            let top_val = self.pop()?;
+                            match !top_val {
+                                Ok(result) => self.push(result),
+                                Err(message) => return Err(run_time_error!(self, "{}", message)),
+                            }
                         }
                         false => {
-                            run_time_error!(&chunk, self.ip, "Error: {}", "Operand must be a boolean");
-                            return InterpretResult::RuntimeError;
+                            return Err(run_time_error!(self, "{}", "Operand must be a boolean"));
                         }
                     }
                 },
@@ -245,13 +1196,15 @@ impl VM {
                     let value = self.peek(0);
                     match value.is_number() {
                         true => {
-                            let top_val = self.pop();
-                            self.push(-top_val);
+                            let top_val = self.pop()?;
+                            match -top_val {
+                                Ok(result) => self.push(result),
+                                Err(message) => return Err(run_time_error!(self, "{}", message)),
+                            }
                         }
 
                         false => {
-                            run_time_error!(&chunk, self.ip, "Error: {}", "Operand must be a number");
-                            return InterpretResult::RuntimeError;
+                            return Err(run_time_error!(self, "{}", "Operand must be a number"));
                         }
                     }
                 },
@@ -259,71 +1212,80 @@ impl VM {
                 OpCode::OpTrue => self.push(Value::ValBool(true)),
                 OpCode::OpFalse => self.push(Value::ValBool(false)),
                 OpCode::OpPop => {
-                    self.pop();
+                    self.pop()?;
                 },
+                OpCode::OpPopN => {
+                    let count = self.read_byte(chunk) as usize;
+                    self.stack.truncate(self.stack.len() - count);
+                },
+                // local slots are relative to the start of the current call frame
                 OpCode::OpGetLocal => {
-                    let slot = self.read_byte(chunk);
-                   // print!("{:?}", &self.stack[slot as u8 as usize]);
-                    self.stack.push(self.stack[slot as u8 as usize].clone())
+                    let slot = self.read_byte(chunk) as usize;
+                    let value = self.stack[self.slot_base() + slot].clone();
+                    self.stack.push(value)
                 },
                 OpCode::OpSetLocal => {
-                    let slot = self.read_byte(chunk);
-                    self.stack[slot as u8 as usize] = self.peek(0).clone();
+                    let slot = self.read_byte(chunk) as usize;
+                    let index = self.slot_base() + slot;
+                    self.stack[index] = self.peek(0).clone();
                 }
-                // get the string value at current location. it will be the name of the global variable.
-                // look up the value in the globals hashmap. If it exists, push it onto the stack
+                // the operand is the slot of the global variable.
+                // If it has been defined, push its value onto the stack
                 // If it does not exist, raise a runtime error
-                OpCode::OpGetGlobal => {
-                    let name = self.read_string(chunk);
+                OpCode::OpGetGlobal | OpCode::OpGetGlobalLong => {
+                    let slot = self.read_operand(chunk, instruction);
 
-                    match self.globals.get(&name){
+                    match &self.globals[slot].value {
                         Some(value) => {
-                            self.push(value.clone());
-                            //println!("{:?}", value);
+                            let value = value.clone();
+                            self.push(value);
                         }
                         None => {
-                            run_time_error!(&chunk, self.ip, "Error: {}", "Undefined variable ".to_owned() + &name);
-                            return InterpretResult::RuntimeError;
+                            return Err(run_time_error!(self, "Undefined variable '{}'.", self.global_names.name(slot)));
                         }
                     }
                 }
-                //i.e we assign the top value in stack to the global name
-                // Add global variable to the globals hashmap
+                //i.e we assign the top value in stack to the global slot
                 // should not check if variable already exists. Redefining a global variable is allowed
-                OpCode::OpDefineGlobal => {
-                    let name = self.read_string(chunk);
-                    self.globals.insert(name, self.peek(0).clone());
-                    self.pop();
+                // a const global can not be redefined, not even by another const
+                OpCode::OpDefineGlobal | OpCode::OpDefineConstGlobal | OpCode::OpDefineGlobalLong | OpCode::OpDefineConstGlobalLong => {
+                    let slot = self.read_operand(chunk, instruction);
+
+                    if self.globals[slot].is_const {
+                        return Err(run_time_error!(self, "Cannot redefine constant '{}'.", self.global_names.name(slot)));
+                    }
+
+                    let is_const = matches!(instruction, OpCode::OpDefineConstGlobal | OpCode::OpDefineConstGlobalLong);
+                    let value = self.pop()?;
+                    self.globals[slot] = Global { value: Some(value), is_const };
                 },
-                // get string value at current location. it will be the name of the global variable.
-                // look up the value in the globals hashmap. If it does not exist, that means it wasnt defined and its a runtime error. 
+                // the operand is the slot of the global variable. If it has not been defined, that means it's a runtime error.
                 // implicit variable declaration is not allowed
-                // If it exists, set the value at that location to the top of the stack
-                OpCode::OpSetGlobal => {
-                    let name = self.read_string(chunk);
+                // If it exists, set the value at that slot to the top of the stack
+                OpCode::OpSetGlobal | OpCode::OpSetGlobalLong => {
+                    let slot = self.read_operand(chunk, instruction);
 
-                    match self.globals.get(&name){
-                        None => {
-                            run_time_error!(&chunk, self.ip, "Error: {}", "Undefined variable ".to_owned() + &name);
-                            return InterpretResult::RuntimeError;
-                        }
-                        Some(_) => {
-                          //  println!("{:?}", self.stack);
-                            self.globals.insert(name, self.peek(0).clone());
-                        }
+                    if self.globals[slot].is_const {
+                        return Err(run_time_error!(self, "Cannot assign to constant '{}'.", self.global_names.name(slot)));
                     }
+
+                    if self.globals[slot].value.is_none() {
+                        return Err(run_time_error!(self, "Undefined variable '{}'.", self.global_names.name(slot)));
+                    }
+
+                    self.globals[slot].value = Some(self.peek(0).clone());
                 },
                 OpCode::OpEqual => {
-                    let a = self.pop();
+                    let a = self.pop()?;
 
-                    let b = self.pop();
+                    let b = self.pop()?;
 
                     self.push(Value::ValBool(a == b));
 
                 }
-                OpCode::OpGreater => binary_op!(>),
-                OpCode::OpLess => binary_op!(<),
+                OpCode::OpGreater => comparison_op!(>),
+                OpCode::OpLess => comparison_op!(<),
             }
-        }        
+        }
     }
-}
\ No newline at end of file
+}
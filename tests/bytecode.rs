@@ -0,0 +1,148 @@
+mod common;
+
+use common::{payload, qkc, with_header, Function};
+use quark::{Error, Value, Vm};
+
+fn compile(source: &str) -> Vec<u8> {
+    Vm::new().compile(source).unwrap()
+}
+
+fn bytecode_error(result: Result<Value, Error>) -> String {
+    match result {
+        Err(Error::Bytecode(message)) => message,
+        other => panic!("expected a bytecode error, got {:?}", other),
+    }
+}
+
+#[test]
+fn compiled_script_runs_like_the_source() {
+    let source = "function fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+class Pair { init(a, b) { this.a = a; this.b = b; } sum() { return this.a + this.b; } }
+create words = \"\";
+for (word in [\"a\", \"b\"]) { words = words + word; }
+words + \"${Pair(fib(10), 1).sum()}\";";
+
+    let bytes = compile(source);
+    assert!(quark::is_bytecode(&bytes));
+
+    let from_bytecode = Vm::new().eval_bytecode(&bytes).unwrap();
+    let from_source = Vm::new().eval(source).unwrap();
+    assert_eq!(from_bytecode.to_display_string(), "ab56");
+    assert_eq!(from_bytecode.to_display_string(), from_source.to_display_string());
+}
+
+#[test]
+fn corrupted_payload_fails_the_checksum() {
+    let mut bytes = compile("1 + 2;");
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+
+    let message = bytecode_error(Vm::new().eval_bytecode(&bytes));
+    assert_eq!(message, "Bytecode checksum does not match, the file is corrupted.");
+}
+
+#[test]
+fn other_format_version_is_rejected() {
+    let bytes = compile("1 + 2;");
+    let bytes = with_header(99, payload(&bytes));
+
+    let message = bytecode_error(Vm::new().eval_bytecode(&bytes));
+    assert_eq!(message, "Unsupported bytecode version 99, expected 2.");
+}
+
+#[test]
+fn trailing_bytes_are_rejected() {
+    let bytes = compile("1 + 2;");
+    let mut extended = payload(&bytes).to_vec();
+    extended.push(0);
+    // the checksum covers the extra byte, so only the length is wrong
+    let bytes = with_header(2, &extended);
+
+    let message = bytecode_error(Vm::new().eval_bytecode(&bytes));
+    assert_eq!(message, "Unexpected bytes after the script.");
+}
+
+#[test]
+fn source_is_not_bytecode() {
+    let message = bytecode_error(Vm::new().eval_bytecode(b"emit 1;"));
+    assert_eq!(message, "Not a compiled quark file.");
+}
+
+#[test]
+fn globals_are_remapped_to_the_slots_of_the_loading_vm() {
+    let bytes = compile("create a = 1; create b = 2; a + b;");
+
+    // the loading VM already handed out slots for other names, and for one of the script's globals
+    let mut vm = Vm::new();
+    vm.set_global("unrelated", 100.0).unwrap();
+    vm.set_global("b", 50.0).unwrap();
+
+    let result = vm.eval_bytecode(&bytes).unwrap();
+    assert_eq!(f64::try_from(result), Ok(3.0));
+    assert_eq!(vm.get_global("a").map(f64::try_from), Some(Ok(1.0)));
+    assert_eq!(vm.get_global("b").map(f64::try_from), Some(Ok(2.0)));
+    assert_eq!(vm.get_global("unrelated").map(f64::try_from), Some(Ok(100.0)));
+}
+
+// a VM that handed out more slots than a one byte operand can address
+fn crowded_vm() -> Vm {
+    let mut vm = Vm::new();
+    for index in 0..300 {
+        vm.set_global(&format!("global{}", index), index as f64).unwrap();
+    }
+    vm
+}
+
+#[test]
+fn script_from_a_fresh_vm_loads_into_a_vm_with_over_256_globals() {
+    // the globals get low slots in the compiling VM, and the jumps cross their instructions
+    let source = "create total = 0;
+create limit = 5;
+function add(n) { total = total + n; }
+for (create i = 0; i < limit; i = i + 1) {
+    if (i == 2) { add(10); } else { add(i); }
+}
+create count = 0;
+while (count < limit) { count = count + 1; }
+for (word in [\"a\", \"b\"]) { if (word == \"b\") add(count); }
+\"${total} ${count}\";";
+
+    let bytes = compile(source);
+    let mut vm = crowded_vm();
+    let result = vm.eval_bytecode(&bytes).unwrap();
+
+    assert_eq!(result.to_display_string(), "23 5");
+    assert_eq!(result.to_display_string(), Vm::new().eval(source).unwrap().to_display_string());
+    assert_eq!(vm.get_global("total").map(f64::try_from), Some(Ok(23.0)));
+    assert_eq!(vm.get_global("global299").map(f64::try_from), Some(Ok(299.0)));
+}
+
+#[test]
+fn runtime_error_lines_survive_saving() {
+    let source = "create g = 1;\nif (g > 0) {\n    g = g + \"x\";\n}";
+
+    let line = |result: Result<Value, Error>| match result {
+        Err(Error::Runtime(error)) => error.trace[0].line,
+        other => panic!("expected a runtime error, got {:?}", other),
+    };
+    assert_eq!(line(crowded_vm().eval_bytecode(&compile(source))), 3);
+    assert_eq!(line(Vm::new().eval(source)), 3);
+}
+
+#[test]
+fn rejected_file_adds_no_globals_to_the_vm() {
+    // names a global, then pops the script itself off its frame, which the verifier rejects
+    let script = Function::script(Vec::new(), vec![15, 7, 6]);
+    let bytes = qkc(&["leftover"], &script);
+
+    let listing = |vm: &mut Vm| {
+        let mut listing = Vec::new();
+        vm.disassemble("create after = 1;", &mut listing).unwrap();
+        String::from_utf8(listing).unwrap()
+    };
+
+    let mut vm = Vm::new();
+    bytecode_error(vm.eval_bytecode(&bytes));
+    // after gets the slot it would get in a VM that never saw the file
+    assert_eq!(listing(&mut vm), listing(&mut Vm::new()));
+}
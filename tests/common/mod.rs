@@ -0,0 +1,167 @@
+/*
+    Builds .qkc files by hand, to load bytecode our compiler would never produce.
+    The layout follows src/bytecode.rs.
+*/
+#![allow(dead_code)]
+
+pub const VERSION: u16 = 2;
+
+pub enum Constant {
+    Void,
+    Number(f64),
+    Str(&'static str),
+    Function(Function),
+}
+
+pub struct Function {
+    pub name: Option<&'static str>,
+    pub arity: u32,
+    pub upvalue_count: u32,
+    pub constants: Vec<Constant>,
+    pub code: Vec<u8>,
+    pub lines: Option<Vec<i32>>, // line, count pairs, one run over the whole code when None
+    pub span_counts: Option<Vec<u32>>, // counts of the span runs, one run over the whole code when None
+}
+
+impl Function {
+    // the top level script
+    pub fn script(constants: Vec<Constant>, code: Vec<u8>) -> Self {
+        Function { name: None, arity: 0, upvalue_count: 0, constants, code, lines: None, span_counts: None }
+    }
+
+    pub fn with_lines(self, lines: Vec<i32>) -> Self {
+        Function { lines: Some(lines), ..self }
+    }
+
+    pub fn with_span_counts(self, span_counts: Vec<u32>) -> Self {
+        Function { span_counts: Some(span_counts), ..self }
+    }
+}
+
+// a complete file holding the script, compiled against the given global names
+pub fn qkc(globals: &[&str], script: &Function) -> Vec<u8> {
+    let mut payload = Vec::new();
+    write_u32(&mut payload, globals.len() as u32);
+    for name in globals {
+        write_str(&mut payload, name);
+    }
+    write_function(&mut payload, script);
+    with_header(VERSION, &payload)
+}
+
+/*
+    A complete file with levels functions declared inside each other under the script, all
+    returning void. Written without recursion, so it can nest deeper than a recursive reader can go.
+*/
+pub fn nested_functions(levels: usize) -> Vec<u8> {
+    // name flag, arity, upvalue count and constant count of a function, and the tag of its one constant
+    let mut prefix = vec![0];
+    write_u32(&mut prefix, 0);
+    write_u32(&mut prefix, 0);
+    write_u32(&mut prefix, 1);
+    prefix.push(5);
+
+    // OP_VOID, OP_RETURN on line 1, and their span
+    let mut suffix = Vec::new();
+    write_u32(&mut suffix, 2);
+    suffix.extend_from_slice(&[7, 6]);
+    write_u32(&mut suffix, 2);
+    suffix.extend_from_slice(&1i32.to_le_bytes());
+    suffix.extend_from_slice(&2i32.to_le_bytes());
+    write_u32(&mut suffix, 1);
+    for field in [1, 1, 0, 0, 2] {
+        write_u32(&mut suffix, field);
+    }
+
+    let mut payload = Vec::new();
+    write_u32(&mut payload, 0);
+    for _ in 0..levels {
+        payload.extend_from_slice(&prefix);
+    }
+    // the innermost function has no constants
+    payload.extend_from_slice(&[0; 13]);
+    for _ in 0..=levels {
+        payload.extend_from_slice(&suffix);
+    }
+    with_header(VERSION, &payload)
+}
+
+// magic, version and checksum in front of the payload
+pub fn with_header(version: u16, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = b"QKC\0".to_vec();
+    bytes.extend_from_slice(&version.to_le_bytes());
+    bytes.extend_from_slice(&checksum(payload).to_le_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+// the payload of a file, after the header
+pub fn payload(bytes: &[u8]) -> &[u8] {
+    &bytes[10..]
+}
+
+// 32 bit FNV-1a, as the file header uses
+pub fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, byte| (hash ^ u32::from(*byte)).wrapping_mul(0x01000193))
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_str(bytes: &mut Vec<u8>, string: &str) {
+    write_u32(bytes, string.len() as u32);
+    bytes.extend_from_slice(string.as_bytes());
+}
+
+fn write_function(bytes: &mut Vec<u8>, function: &Function) {
+    match function.name {
+        Some(name) => {
+            bytes.push(1);
+            write_str(bytes, name);
+        },
+        None => bytes.push(0),
+    }
+    write_u32(bytes, function.arity);
+    write_u32(bytes, function.upvalue_count);
+
+    write_u32(bytes, function.constants.len() as u32);
+    for constant in &function.constants {
+        match constant {
+            Constant::Void => bytes.push(0),
+            Constant::Number(number) => {
+                bytes.push(3);
+                bytes.extend_from_slice(&number.to_le_bytes());
+            },
+            Constant::Str(string) => {
+                bytes.push(4);
+                write_str(bytes, string);
+            },
+            Constant::Function(function) => {
+                bytes.push(5);
+                write_function(bytes, function);
+            },
+        }
+    }
+
+    write_u32(bytes, function.code.len() as u32);
+    bytes.extend_from_slice(&function.code);
+
+    let code_len = function.code.len();
+    let lines = function.lines.clone().unwrap_or_else(|| vec![1, code_len as i32]);
+    write_u32(bytes, lines.len() as u32);
+    for line in lines {
+        bytes.extend_from_slice(&line.to_le_bytes());
+    }
+
+    // every span at the start of line 1
+    let span_counts = function.span_counts.clone().unwrap_or_else(|| vec![code_len as u32]);
+    write_u32(bytes, span_counts.len() as u32);
+    for count in span_counts {
+        bytes.extend_from_slice(&1i32.to_le_bytes());
+        bytes.extend_from_slice(&1i32.to_le_bytes());
+        write_u32(bytes, 0);
+        write_u32(bytes, 0);
+        write_u32(bytes, count);
+    }
+}
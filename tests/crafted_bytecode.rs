@@ -0,0 +1,176 @@
+mod common;
+
+use common::{nested_functions, qkc, Constant, Function};
+use quark::{Error, Vm};
+
+// opcodes, see chunk::OpCode::from_byte
+const OP_CONSTANT: u8 = 0;
+const OP_ADD: u8 = 1;
+const OP_RETURN: u8 = 6;
+const OP_VOID: u8 = 7;
+const OP_EMIT: u8 = 14;
+const OP_POP: u8 = 15;
+const OP_GET_LOCAL: u8 = 19;
+const OP_GET_UPVALUE: u8 = 26;
+const OP_LOOP: u8 = 23;
+const OP_POP_N: u8 = 56;
+const OP_FOR_ITER: u8 = 58;
+
+fn bytecode_error(bytes: &[u8]) -> String {
+    match Vm::new().eval_bytecode(bytes) {
+        Err(Error::Bytecode(message)) => message,
+        other => panic!("expected the bytecode to be rejected, got {:?}", other),
+    }
+}
+
+fn rejected_script(code: Vec<u8>, constants: Vec<Constant>) -> String {
+    bytecode_error(&qkc(&[], &Function::script(constants, code)))
+}
+
+fn runtime_error(result: Result<quark::Value, Error>) -> String {
+    match result {
+        Err(Error::Runtime(error)) => error.message,
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+}
+
+#[test]
+fn for_in_position_inside_a_character_is_a_runtime_error() {
+    // for (c in "é") { emit c; } with the position starting in the middle of 'é'
+    let script = Function::script(
+        vec![Constant::Str("é"), Constant::Number(1.0)],
+        vec![
+            OP_CONSTANT, 0,
+            OP_CONSTANT, 1,
+            OP_FOR_ITER, 1, 0, 7,
+            OP_GET_LOCAL, 3,
+            OP_EMIT,
+            OP_POP,
+            OP_LOOP, 0, 11,
+            OP_POP_N, 2,
+            OP_VOID,
+            OP_RETURN,
+        ],
+    );
+
+    let mut vm = Vm::new();
+    let message = runtime_error(vm.eval_bytecode(&qkc(&[], &script)));
+    assert_eq!(message, "Loop position is not at a character of the string.");
+}
+
+#[test]
+fn script_capturing_variables_is_rejected() {
+    // reads upvalue 0, which the VM never gives the top level script
+    let mut script = Function::script(Vec::new(), vec![OP_GET_UPVALUE, 0, OP_EMIT, OP_VOID, OP_RETURN]);
+    script.upvalue_count = 1;
+
+    let message = bytecode_error(&qkc(&[], &script));
+    assert_eq!(message, "Invalid bytecode in script: a script can not take parameters or capture variables.");
+}
+
+#[test]
+fn script_taking_parameters_is_rejected() {
+    let mut script = Function::script(Vec::new(), vec![OP_GET_LOCAL, 1, OP_EMIT, OP_VOID, OP_RETURN]);
+    script.arity = 1;
+
+    let message = bytecode_error(&qkc(&[], &script));
+    assert_eq!(message, "Invalid bytecode in script: a script can not take parameters or capture variables.");
+}
+
+#[test]
+fn unknown_opcode_is_rejected() {
+    // loading walks the instructions to remap globals, so it finds these before the verifier
+    let message = rejected_script(vec![200, OP_VOID, OP_RETURN], Vec::new());
+    assert_eq!(message, "Invalid opcode 200 at offset 0.");
+}
+
+#[test]
+fn cut_off_operand_is_rejected() {
+    let message = rejected_script(vec![OP_VOID, OP_RETURN, OP_CONSTANT], vec![Constant::Number(1.0)]);
+    assert_eq!(message, "Operand of the instruction at offset 2 is cut off.");
+}
+
+#[test]
+fn stack_underflow_is_rejected() {
+    // slot zero holds the script itself and can not be popped
+    let message = rejected_script(vec![OP_POP, OP_VOID, OP_RETURN], Vec::new());
+    assert_eq!(message, "Invalid bytecode in script: instruction at offset 0 pops more values than the frame has.");
+}
+
+#[test]
+fn out_of_range_constant_is_rejected() {
+    let message = rejected_script(vec![OP_CONSTANT, 5, OP_EMIT, OP_VOID, OP_RETURN], vec![Constant::Void]);
+    assert_eq!(message, "Invalid bytecode in script: constant 5 at offset 0 does not exist.");
+}
+
+#[test]
+fn nested_function_reading_a_missing_upvalue_is_rejected() {
+    let nested = Function { name: Some("inner"), ..Function::script(Vec::new(), vec![OP_GET_UPVALUE, 0, OP_RETURN]) };
+    let script = Function::script(vec![Constant::Function(nested)], vec![OP_VOID, OP_RETURN]);
+
+    let message = bytecode_error(&qkc(&[], &script));
+    assert_eq!(message, "Invalid bytecode in inner: upvalue 0 at offset 0 does not exist.");
+}
+
+#[test]
+fn deeply_nested_functions_are_rejected() {
+    // deep enough to overflow the stack when read recursively
+    let message = bytecode_error(&nested_functions(200_000));
+    assert_eq!(message, "Functions are nested too deeply.");
+
+    let message = bytecode_error(&nested_functions(257));
+    assert_eq!(message, "Functions are nested too deeply.");
+}
+
+#[test]
+fn functions_nested_up_to_the_limit_load() {
+    let mut vm = Vm::new();
+    assert!(vm.eval_bytecode(&nested_functions(256)).is_ok());
+}
+
+// emit 1 then return void, five bytes
+fn emitting_script() -> Function {
+    Function::script(vec![Constant::Number(1.0)], vec![OP_CONSTANT, 0, OP_EMIT, OP_VOID, OP_RETURN])
+}
+
+#[test]
+fn line_runs_adding_up_past_the_code_are_rejected() {
+    // the runs would overflow an i32 when looking up the line of the second byte
+    let message = bytecode_error(&qkc(&[], &emitting_script().with_lines(vec![1, 1, 1, i32::MAX])));
+    assert_eq!(message, "Line table of a function does not cover its code.");
+}
+
+#[test]
+fn empty_or_negative_line_runs_are_rejected() {
+    for lines in [vec![1, 0, 2, 5], vec![1, -1, 2, 6]] {
+        let message = bytecode_error(&qkc(&[], &emitting_script().with_lines(lines)));
+        assert_eq!(message, "Line table of a function has an invalid run length.");
+    }
+}
+
+#[test]
+fn line_table_shorter_than_the_code_is_rejected() {
+    let message = bytecode_error(&qkc(&[], &emitting_script().with_lines(vec![1, 2, 2, 1])));
+    assert_eq!(message, "Line table of a function does not cover its code.");
+}
+
+#[test]
+fn span_runs_not_covering_the_code_are_rejected() {
+    let message = bytecode_error(&qkc(&[], &emitting_script().with_span_counts(vec![1, u32::MAX])));
+    assert_eq!(message, "Span table of a function does not cover its code.");
+
+    let message = bytecode_error(&qkc(&[], &emitting_script().with_span_counts(vec![0, 5])));
+    assert_eq!(message, "Span table of a function has an invalid run length.");
+}
+
+#[test]
+fn runtime_error_reports_the_line_from_a_loaded_table() {
+    // 1 + void fails on the OP_ADD at offset 4, which the table puts on line 3
+    let script = Function::script(vec![Constant::Number(1.0)], vec![OP_CONSTANT, 0, OP_VOID, OP_VOID, OP_ADD, OP_RETURN])
+        .with_lines(vec![1, 2, 2, 2, 3, 2]);
+
+    match Vm::new().eval_bytecode(&qkc(&[], &script)) {
+        Err(Error::Runtime(error)) => assert_eq!(error.trace[0].line, 3),
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+}
@@ -0,0 +1,54 @@
+use quark::Vm;
+
+// line and column of every diagnostic of the source
+fn positions(source: &str) -> Vec<(i32, i32)> {
+    Vm::new().check(source).iter().map(|diagnostic| (diagnostic.span.line, diagnostic.span.column)).collect()
+}
+
+#[test]
+fn columns_count_characters_not_bytes() {
+    assert_eq!(positions("create s = \"üü\"; emit s +;"), [(1, 26)]);
+}
+
+#[test]
+fn columns_start_over_after_newlines_in_comments_and_strings() {
+    assert_eq!(positions("/* a\n  b */ emit 1 +;"), [(2, 16)]);
+    assert_eq!(positions("emit \"\"\"\n  ä\n\"\"\" +;"), [(3, 6)]);
+}
+
+#[test]
+fn columns_on_a_long_line() {
+    // one line of 40k tokens, the missing operand is after the last of them
+    let source = format!("emit {} +;", vec!["1"; 20_000].join(" + "));
+    assert_eq!(positions(&source), [(1, source.len() as i32)]);
+}
+
+// a function declaring locals v1 to vcount, one per line after the line it starts on, returning the last
+fn function_with_locals(count: usize) -> String {
+    let locals: Vec<String> = (1..=count).map(|index| format!("create v{} = {};", index, index)).collect();
+    format!("function f() {{\n{}\nreturn v{};\n}}\nf();", locals.join("\n"), count)
+}
+
+#[test]
+fn function_can_have_256_slots() {
+    // slot zero holds the function, so 255 more locals fit
+    let result = Vm::new().eval(&function_with_locals(255)).unwrap();
+    assert_eq!(f64::try_from(result), Ok(255.0));
+}
+
+#[test]
+fn local_past_the_last_slot_is_an_error() {
+    let diagnostics = Vm::new().check(&function_with_locals(300));
+    let first = &diagnostics[0];
+    assert_eq!(first.message, "Too many local variables in function.");
+    assert_eq!(first.location.as_deref(), Some("'v256'"));
+    assert_eq!(first.span.line, 257);
+}
+
+#[test]
+fn for_in_past_the_last_slot_is_an_error() {
+    // the loop needs two hidden locals and the loop variable
+    let source = function_with_locals(253).replace("return", "for (item in [1]) emit item;\nreturn");
+    let diagnostics = Vm::new().check(&source);
+    assert_eq!(diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect::<Vec<_>>(), ["Too many local variables in function."]);
+}
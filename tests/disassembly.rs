@@ -0,0 +1,28 @@
+use quark::Vm;
+
+fn listing(source: &str) -> String {
+    let mut listing = Vec::new();
+    Vm::new().disassemble(source, &mut listing).unwrap();
+    String::from_utf8(listing).unwrap()
+}
+
+// the name written after the slot of every instruction with this opcode, in listing order
+fn names<'a>(listing: &'a str, opcode: &str) -> Vec<&'a str> {
+    listing.lines()
+        .filter_map(|line| line.split_once(&format!("{} ", opcode)))
+        .map(|(_, operands)| {
+            let (slot, name) = operands.trim().split_once(' ').unwrap();
+            assert!(slot.parse::<usize>().is_ok(), "{} is not a slot", slot);
+            name
+        })
+        .collect()
+}
+
+#[test]
+fn global_slots_are_shown_with_their_names() {
+    let listing = listing("create first = 1;\ncreate second = 2;\nfunction bump() { second = first + second; }\nbump();");
+
+    assert_eq!(names(&listing, "OP_DEFINE_GLOBAL"), ["first", "second", "bump"], "{}", listing);
+    assert_eq!(names(&listing, "OP_GET_GLOBAL"), ["bump", "first", "second"], "{}", listing);
+    assert_eq!(names(&listing, "OP_SET_GLOBAL"), ["second"], "{}", listing);
+}
@@ -0,0 +1,103 @@
+use quark::{Error, Value, Vm};
+
+fn number(result: Result<Value, Error>) -> f64 {
+    f64::try_from(result.unwrap()).unwrap()
+}
+
+#[test]
+fn eval_returns_the_value_of_the_last_expression_statement() {
+    let mut vm = Vm::new();
+    assert_eq!(number(vm.eval("1 + 2 * 3;")), 7.0);
+    assert_eq!(vm.eval("\"a\" + \"b\";").unwrap().to_display_string(), "ab");
+    // a script that does not end with an expression statement returns void
+    assert_eq!(vm.eval("create x = 1;").unwrap(), Value::ValVoid(()));
+}
+
+#[test]
+fn globals_persist_across_eval_calls() {
+    let mut vm = Vm::new();
+    vm.eval("create count = 1; function bump() { count = count + 1; }").unwrap();
+    vm.eval("bump(); bump();").unwrap();
+
+    assert_eq!(number(vm.eval("count;")), 3.0);
+    assert_eq!(vm.get_global("count").map(f64::try_from), Some(Ok(3.0)));
+    assert_eq!(vm.get_global("undefined"), None);
+}
+
+#[test]
+fn host_globals_are_visible_to_scripts() {
+    let mut vm = Vm::new();
+    vm.set_global("limit", 10.0).unwrap();
+    assert_eq!(number(vm.eval("limit * 2;")), 20.0);
+
+    vm.set_global("limit", 5.0).unwrap();
+    assert_eq!(number(vm.eval("limit * 2;")), 10.0);
+}
+
+#[test]
+fn set_global_refuses_to_replace_a_constant() {
+    let mut vm = Vm::new();
+    vm.eval("const answer = 42;").unwrap();
+
+    assert_eq!(vm.set_global("answer", 1.0), Err(Error::Global(String::from("Cannot assign to constant 'answer'."))));
+    assert_eq!(number(vm.eval("answer;")), 42.0);
+}
+
+#[test]
+fn native_called_with_the_wrong_number_of_arguments_is_a_runtime_error() {
+    let mut vm = Vm::new();
+    vm.define_native("double", 1, |args| match args[0] {
+        Value::ValNumber(n) => Ok(Value::from(n * 2.0)),
+        _ => Err(String::from("double expects a number")),
+    }).unwrap();
+
+    assert_eq!(number(vm.eval("double(4);")), 8.0);
+    match vm.eval("double(1, 2);") {
+        Err(Error::Runtime(error)) => assert_eq!(error.message, "Expected 1 arguments but got 2."),
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+}
+
+#[test]
+fn native_error_is_surfaced_as_a_runtime_error() {
+    let mut vm = Vm::new();
+    vm.define_native("fail", 0, |_| Err(String::from("the host said no"))).unwrap();
+
+    match vm.eval("function call() { return fail(); }\ncall();") {
+        Err(Error::Runtime(error)) => {
+            assert_eq!(error.message, "the host said no");
+            assert_eq!(error.trace.len(), 2);
+        },
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+}
+
+#[test]
+fn compile_errors_are_returned_and_nothing_runs() {
+    let mut vm = Vm::new();
+    match vm.eval("create ran = true; emit ;") {
+        Err(Error::Compile(diagnostics)) => assert_eq!(diagnostics[0].message, "Expecting expression."),
+        other => panic!("expected a compile error, got {:?}", other),
+    }
+    assert_eq!(vm.get_global("ran"), None);
+}
+
+#[test]
+fn collections_are_read_through_the_exported_object_types() {
+    let mut vm = Vm::new();
+    let list = match vm.eval("[\"a\", 2];").unwrap() {
+        Value::ValObject(quark::Object::ObjList(list)) => list,
+        other => panic!("expected a list, got {:?}", other),
+    };
+    let first = match &list.borrow().items[0] {
+        Value::ValObject(quark::Object::ObjString(string)) => string.as_str().to_string(),
+        other => panic!("expected a string, got {:?}", other),
+    };
+    assert_eq!(first, "a");
+
+    let map = match vm.eval("create m = {\"key\": 1}; m;").unwrap() {
+        Value::ValObject(quark::Object::ObjMap(map)) => map,
+        other => panic!("expected a map, got {:?}", other),
+    };
+    assert_eq!(map.borrow().len(), 1);
+}
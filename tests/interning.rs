@@ -0,0 +1,47 @@
+use quark::Vm;
+
+// constant operands of every OP_CONSTANT in the listing, as index and value
+fn constants(listing: &str) -> Vec<(usize, String)> {
+    listing.lines()
+        .filter(|line| line.contains("OP_CONSTANT "))
+        .map(|line| {
+            let mut operands = line.split("OP_CONSTANT").nth(1).unwrap().split_whitespace();
+            let index = operands.next().unwrap().parse().unwrap();
+            (index, operands.collect::<Vec<_>>().join(" "))
+        })
+        .collect()
+}
+
+#[test]
+fn repeated_literals_share_a_constant() {
+    let mut vm = Vm::new();
+    let mut listing = Vec::new();
+    vm.disassemble("emit \"x\"; emit 2; emit \"x\"; emit 2; emit \"x\";", &mut listing).unwrap();
+
+    let constants = constants(&String::from_utf8(listing).unwrap());
+    assert_eq!(constants.len(), 5);
+    for (index, value) in &constants {
+        let expected = if value == "'x'" { constants[0].0 } else { constants[1].0 };
+        assert_eq!(*index, expected, "{} was not deduplicated", value);
+    }
+    assert_ne!(constants[0].0, constants[1].0);
+}
+
+#[test]
+fn strings_from_different_chunks_and_runs_are_equal() {
+    let mut vm = Vm::new();
+    let from_function = vm.eval("function name() { return \"quark\"; } name();").unwrap();
+    let from_script = vm.eval("\"quark\";").unwrap();
+    let concatenated = vm.eval("\"qu\" + \"ark\";").unwrap();
+
+    assert_eq!(from_function, from_script);
+    assert_eq!(concatenated, from_script);
+}
+
+#[test]
+fn host_strings_are_interned_when_passed_in() {
+    let mut vm = Vm::new();
+    vm.set_global("key", "name").unwrap();
+    let found = vm.eval("create table = {\"name\": 1}; table[key];").unwrap();
+    assert_eq!(f64::try_from(found), Ok(1.0));
+}
@@ -0,0 +1,84 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use quark::Vm;
+
+// in-memory writer the test keeps a handle to while the VM owns a clone
+#[derive(Clone, Default)]
+struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Captured {
+    fn text(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+impl Write for Captured {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn capturing_vm() -> (Vm, Captured, Captured) {
+    let (output, diagnostics) = (Captured::default(), Captured::default());
+    let mut vm = Vm::new();
+    vm.set_output(output.clone());
+    vm.set_diagnostics(diagnostics.clone());
+    vm.set_source_name("capture.quark");
+    (vm, output, diagnostics)
+}
+
+#[test]
+fn emit_writes_to_the_output_writer() {
+    let (mut vm, output, diagnostics) = capturing_vm();
+    vm.eval("emit 1 + 2; emit \"two\";").unwrap();
+    vm.eval("emit [1, 2];").unwrap();
+
+    assert_eq!(output.text(), "'3'\n'two'\n'[1, 2]'\n");
+    assert_eq!(diagnostics.text(), "");
+}
+
+#[test]
+fn errors_are_written_to_the_diagnostics_writer() {
+    let (mut vm, output, diagnostics) = capturing_vm();
+    assert!(vm.eval("emit \"before\";\nemit -\"text\";").is_err());
+
+    assert_eq!(output.text(), "'before'\n");
+    assert_eq!(diagnostics.text(), "capture.quark:2:6: Error: Operand must be a number
+2 | emit -\"text\";
+  |      ^
+[line 2] in script
+");
+
+    let diagnostics_before = diagnostics.text().len();
+    assert!(vm.eval("emit ;").is_err());
+    assert_eq!(&diagnostics.text()[diagnostics_before..], "capture.quark:1:6: Error at ';': Expecting expression.
+1 | emit ;
+  |      ^
+");
+}
+
+#[test]
+fn list_holding_itself_is_printed_with_an_ellipsis() {
+    let (mut vm, output, diagnostics) = capturing_vm();
+    vm.eval("create a = [1]; a.push(a); emit a; emit \"a is ${a}\"; emit [[2], a];").unwrap();
+
+    assert_eq!(output.text(), "'[1, [...]]'\n'a is [1, [...]]'\n'[[2], [1, [...]]]'\n");
+    assert_eq!(diagnostics.text(), "");
+}
+
+#[test]
+fn map_holding_itself_is_printed_with_an_ellipsis() {
+    let (mut vm, output, diagnostics) = capturing_vm();
+    vm.eval("create m = {\"n\": 1}; m[\"self\"] = m; m[\"list\"] = [m]; emit m; emit \"${m}\";").unwrap();
+
+    let printed = "'{\"n\": 1, \"self\": {...}, \"list\": [{...}]}'\n";
+    assert_eq!(output.text(), printed.repeat(2));
+    assert_eq!(diagnostics.text(), "");
+}
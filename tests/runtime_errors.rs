@@ -0,0 +1,50 @@
+use quark::{Error, RuntimeError, TraceFrame, Vm};
+
+fn runtime_error(vm: &mut Vm, source: &str) -> RuntimeError {
+    match vm.eval(source) {
+        Err(Error::Runtime(error)) => error,
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+}
+
+const NESTED: &str = "function inner(x) {
+    return x / \"two\";
+}
+function outer(x) {
+    return inner(x) + 1;
+}
+outer(4);
+";
+
+#[test]
+fn error_points_at_the_operator_in_the_innermost_call() {
+    let error = runtime_error(&mut Vm::new(), NESTED);
+
+    assert_eq!(error.message, "Operands must be numbers");
+    assert_eq!((error.span.line, error.span.column), (2, 14));
+    assert_eq!(error.trace, vec![
+        TraceFrame { function: Some(String::from("inner")), line: 2 },
+        TraceFrame { function: Some(String::from("outer")), line: 5 },
+        TraceFrame { function: None, line: 7 },
+    ]);
+}
+
+#[test]
+fn rendered_trace_shows_the_source_line_and_every_frame() {
+    let error = runtime_error(&mut Vm::new(), NESTED);
+
+    let expected = "nested.quark:2:14: Error: Operands must be numbers
+2 |     return x / \"two\";
+  |              ^
+[line 2] in inner()
+[line 5] in outer()
+[line 7] in script";
+    assert_eq!(Error::Runtime(error).render("nested.quark", Some(NESTED)), expected);
+}
+
+#[test]
+fn undefined_variable_names_it_in_quotes() {
+    let mut vm = Vm::new();
+    assert_eq!(runtime_error(&mut vm, "missing;").message, "Undefined variable 'missing'.");
+    assert_eq!(runtime_error(&mut vm, "missing = 1;").message, "Undefined variable 'missing'.");
+}
@@ -0,0 +1,22 @@
+use quark::Vm;
+
+fn string(source: &str) -> String {
+    Vm::new().eval(source).unwrap().to_display_string()
+}
+
+#[test]
+fn multiline_string_closed_at_column_zero_keeps_every_line() {
+    assert_eq!(string("\"\"\"\n    first\n    second\n\"\"\";"), "    first\n    second");
+    assert_eq!(string("\"\"\"\n    first\n\n    second\n\"\"\";"), "    first\n\n    second");
+}
+
+#[test]
+fn multiline_string_is_dedented_to_its_closing_quotes() {
+    assert_eq!(string("\"\"\"\n    first\n      second\n    \"\"\";"), "first\n  second");
+    assert_eq!(string("\"\"\"\n    first\n      second\n  \"\"\";"), "  first\n    second");
+}
+
+#[test]
+fn multiline_string_ending_in_a_blank_line_keeps_it() {
+    assert_eq!(string("\"\"\"\n  first\n\n  \"\"\";"), "first\n");
+}